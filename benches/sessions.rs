@@ -0,0 +1,167 @@
+//! Throughput of private requests while slow topic handlers are ticking.
+//!
+//! Runs the same workload three times:
+//! - `global mutex`: the way `NitramState` used to be shared, one
+//!   `tokio::sync::Mutex` that server message ticks hold while the topic
+//!   handlers are awaited, and that private requests lock to check the
+//!   session.
+//! - `single shard`: a `MemoryBackend` with one shard, every session behind
+//!   the same `RwLock<HashMap>`, never held across an await.
+//! - `sharded`: the default shards, where unrelated sessions rarely wait for
+//!   each other.
+//!
+//! Run with `cargo bench --bench sessions`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use nitram::{
+    auth::WSSessionAuthedResource, backend::MemoryBackend, error::MethodError, models::UserSession,
+    IntoParams, Nitram, NitramBuilder,
+};
+
+const SESSIONS: usize = 1_000;
+const REQUESTS_PER_SESSION: usize = 20;
+const SUBSCRIBED_SESSIONS: usize = 50;
+const TOPIC_HANDLER_DELAY: Duration = Duration::from_millis(5);
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Deserialize, Serialize)]
+struct EchoParams {
+    code: String,
+}
+impl IntoParams for EchoParams {}
+
+#[derive(Deserialize, Serialize)]
+struct SlowParams {}
+impl IntoParams for SlowParams {}
+
+async fn echo(
+    _session: WSSessionAuthedResource,
+    params: EchoParams,
+) -> Result<String, MethodError> {
+    Ok(params.code)
+}
+
+async fn slow_topic(
+    _session: WSSessionAuthedResource,
+    _params: SlowParams,
+) -> Result<Value, MethodError> {
+    tokio::time::sleep(TOPIC_HANDLER_DELAY).await;
+    Ok(json!("tick"))
+}
+
+async fn prepare(backend: MemoryBackend) -> (Arc<Nitram>, Vec<Uuid>) {
+    let nitram = NitramBuilder::default()
+        .set_session_backend(backend)
+        .add_private_handler("Echo", echo)
+        .add_server_message_handler("Slow", slow_topic)
+        .build();
+    let mut ids = Vec::with_capacity(SESSIONS);
+    for i in 0..SESSIONS {
+        let id = nitram.insert().await;
+        nitram
+            ._auth_ws_session(
+                id,
+                UserSession {
+                    id: Uuid::new_v4(),
+                    user_id: format!("user_{}", i),
+                    expires_at: Utc::now() + chrono::Duration::hours(1),
+                },
+            )
+            .await;
+        ids.push(id);
+    }
+    let register = json!({
+        "id": "register",
+        "method": "nitram_topic_register",
+        "params": { "topic": "Slow", "handler_params": {} },
+    })
+    .to_string();
+    for id in ids.iter().take(SUBSCRIBED_SESSIONS) {
+        nitram.send(register.clone(), id).await;
+    }
+    (Arc::new(nitram), ids)
+}
+
+/// Returns the requests per second. With `global_mutex`, the ticks hold the
+/// mutex while the topic handlers run and the requests wait for it.
+async fn run(backend: MemoryBackend, global_mutex: bool) -> f64 {
+    let (nitram, ids) = prepare(backend).await;
+    let mutex = Arc::new(Mutex::new(()));
+
+    // -- Server message ticks
+    let mut tickers = vec![];
+    for &id in &ids[..SUBSCRIBED_SESSIONS] {
+        let nitram = nitram.clone();
+        let mutex = mutex.clone();
+        tickers.push(tokio::spawn(async move {
+            loop {
+                {
+                    let _state = match global_mutex {
+                        true => Some(mutex.lock().await),
+                        false => None,
+                    };
+                    nitram.get_server_messages_for_session(&id).await;
+                }
+                tokio::time::sleep(TICK_INTERVAL).await;
+            }
+        }));
+    }
+
+    // -- Requests
+    let start = Instant::now();
+    let mut clients = vec![];
+    for &id in &ids {
+        let nitram = nitram.clone();
+        let mutex = mutex.clone();
+        clients.push(tokio::spawn(async move {
+            for i in 0..REQUESTS_PER_SESSION {
+                let req = json!({
+                    "id": i.to_string(),
+                    "method": "Echo",
+                    "params": { "code": "hello" },
+                })
+                .to_string();
+                if global_mutex {
+                    // `is_auth` locked the state, then released it
+                    drop(mutex.lock().await);
+                }
+                nitram.send(req, &id).await;
+            }
+        }));
+    }
+    for client in clients {
+        client.await.expect("client task panicked");
+    }
+    let elapsed = start.elapsed();
+    for ticker in tickers {
+        ticker.abort();
+    }
+
+    (SESSIONS * REQUESTS_PER_SESSION) as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{} sessions x {} requests, {} sessions subscribed to a {:?} topic handler",
+        SESSIONS, REQUESTS_PER_SESSION, SUBSCRIBED_SESSIONS, TOPIC_HANDLER_DELAY
+    );
+    let global_mutex = run(MemoryBackend::with_shards(1), true).await;
+    println!("global mutex: {:>12.0} req/s", global_mutex);
+    let single_shard = run(MemoryBackend::with_shards(1), false).await;
+    println!("single shard: {:>12.0} req/s", single_shard);
+    let sharded = run(MemoryBackend::new(), false).await;
+    println!("sharded:      {:>12.0} req/s", sharded);
+    println!(
+        "speedup:      {:>12.1}x over the global mutex",
+        sharded / global_mutex
+    );
+}
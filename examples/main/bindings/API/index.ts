@@ -1,15 +1,23 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AuthenticateParams } from "./Params";
 import type { GetTokenParams } from "./Params";
 import type { IdParams } from "../Nitram";
 import type { MessagesOutput } from "../MessagesOutput";
 import type { MessagesParams } from "./Params";
+import type { RefreshParams } from "./Params";
 import type { SendMessageParams } from "./Params";
 import type { User } from "../User";
 
+export type AuthenticateAPI = { i: AuthenticateParams, o: string, };
+
 export type GetTokenAPI = { i: GetTokenParams, o: string, };
 
 export type GetUserAPI = { i: IdParams, o: User, };
 
+export type HistoryAPI = { i: MessagesParams, o: string, };
+
 export type MessagesAPI = { i: MessagesParams, o: MessagesOutput, };
 
+export type RefreshAPI = { i: RefreshParams, o: string, };
+
 export type SendMessageAPI = { i: SendMessageParams, o: Array<string>, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Chat errors, the web app matches on their `code`
+ */
+export type ChatError = { "code": "empty_message" } | { "code": "message_too_long", "data": { max: number, } };
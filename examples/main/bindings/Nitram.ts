@@ -1,3 +1,15 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
+export type EmptyParams = null;
+
 export type IdParams = { id: string, };
+
+/**
+ * A param that failed to deserialize or validate. Bad request responses
+ * carry the list of them as data.
+ */
+export type ParamError = { 
+/**
+ * e.g. `params.channel`
+ */
+path: string, message: string, };
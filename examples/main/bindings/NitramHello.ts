@@ -0,0 +1,43 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type NitramCapabilities = { methods: Array<string>, 
+/**
+ * Topics of server messages
+ */
+topics: Array<string>, };
+
+/**
+ * The first frame sent on a websocket, as the payload of a `nitram_hello`
+ * server message. The client can check it is compatible and adapt its
+ * keepalive and queueing.
+ */
+export type NitramHello = { ws_session_id: string, 
+/**
+ * Set when the websocket is authenticated, during the handshake or by
+ * resuming a session
+ */
+user_id: string | null, 
+/**
+ * e.g. `2`, see `ProtocolVersion`
+ */
+protocol_version: string, 
+/**
+ * e.g. `json`, see `Encoding`
+ */
+encoding: string, server_time: string, ping_interval_in_seconds: number, 
+/**
+ * The websocket is closed when no pong is received for this long
+ */
+timeout_in_seconds: number, max_frame_size: number, max_concurrent_requests: number, max_batch_size: number, 
+/**
+ * Protocol versions the server speaks, e.g. `["2", "jsonrpc"]`
+ */
+supported_protocol_versions: Array<string>, 
+/**
+ * Available without authentication
+ */
+public: NitramCapabilities, 
+/**
+ * Available once authenticated
+ */
+private: NitramCapabilities, };
@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { JsonValue } from "./serde_json/JsonValue";
+
+/**
+ * An item of a stream handler, sent before the `NitramResponse` of the
+ * request.
+ */
+export type NitramPartialResponse = { id: string, method: string, partial: JsonValue, };
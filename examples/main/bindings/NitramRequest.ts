@@ -1,4 +1,9 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 import type { JsonValue } from "./serde_json/JsonValue";
 
-export type NitramRequest = { id: string, method: string, params: JsonValue, };
+export type NitramRequest = { id: string, method: string, params: JsonValue, 
+/**
+ * When the client stops waiting for the response. The request times out
+ * then if it is still in flight, see `NitramBuilder::set_request_timeout`
+ */
+deadline?: string, };
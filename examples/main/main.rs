@@ -1,7 +1,7 @@
 use actix_files::NamedFile;
 use actix_web::{middleware::Logger, web, App, HttpServer};
 use chrono::{DateTime, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{encode, EncodingKey, Header};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
@@ -14,15 +14,18 @@ use ts_rs::TS;
 use uuid::Uuid;
 
 use nitram::{
-    auth::{WSSessionAnonymResource, WSSessionAuthedResource},
-    error::{MethodError, MethodResult},
+    auth::WSSessionAuthedResource,
+    error::{AppError, MethodError, MethodResult},
+    jwt::JwtAuth,
     models::Store,
-    nitram_handler, ws, AuthenticateParams, FromResources, IdParams, IntoParams, NitramBuilder,
+    nitram_handler,
+    stream::NitramStream,
+    ws, FromResources, IdParams, IntoParams, NitramBuilder,
 };
 
 const JWT_SECRET: &[u8] = b"nitram-example-secret-change-in-production";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 struct JwtClaims {
     sub: String, // user_id
     exp: usize,  // expiry (unix timestamp)
@@ -125,12 +128,42 @@ nitram_handler!(
     user_name: String
 );
 
+const MAX_MESSAGE_LENGTH: usize = 500;
+
+/// Chat errors, the web app matches on their `code`
+#[derive(Debug, Serialize, TS)]
+#[serde(tag = "code", content = "data", rename_all = "snake_case")]
+#[ts(export)]
+enum ChatError {
+    EmptyMessage,
+    MessageTooLong { max: usize },
+}
+impl AppError for ChatError {}
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChatError::EmptyMessage => write!(f, "empty message"),
+            ChatError::MessageTooLong { max } => {
+                write!(f, "message longer than {} characters", max)
+            }
+        }
+    }
+}
+
 async fn send_message_handler(
     resource: NitramResource,
     session: WSSessionAuthedResource,
     mut store: Store,
     params: SendMessageParams,
 ) -> MethodResult<Vec<String>> {
+    if params.message.trim().is_empty() {
+        Err(ChatError::EmptyMessage)?;
+    }
+    if params.message.chars().count() > MAX_MESSAGE_LENGTH {
+        Err(ChatError::MessageTooLong {
+            max: MAX_MESSAGE_LENGTH,
+        })?;
+    }
     let count = store.get::<i32>("count").await.unwrap_or_default();
     let now = Utc::now();
     store.insert("last", json!(now)).await;
@@ -148,53 +181,9 @@ nitram_handler!(
     Vec<String>,       // Return type
     // Params
     message: String,
-    channel: String
+    channel: String => [length(1..=20), regex("^[a-z0-9-]+$")]
 );
 
-async fn authenticate_handler(
-    resource: NitramResource,
-    anonym_session: WSSessionAnonymResource,
-    params: AuthenticateParams,
-) -> MethodResult<String> {
-    let db = resource.db;
-    let token = params.token.clone();
-
-    tracing::debug!("Authenticating {}", token);
-
-    let token_data = decode::<JwtClaims>(
-        &token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &Validation::default(),
-    )
-    .map_err(|e| {
-        tracing::debug!("JWT decode error: {:?}", e);
-        MethodError::NotAuthenticated
-    })?;
-
-    let user_id = token_data.claims.sub;
-    let expires_at =
-        DateTime::from_timestamp(token_data.claims.exp as i64, 0).unwrap_or_else(Utc::now);
-
-    let user = {
-        let users = db.users.lock().await;
-        users.get(&user_id).cloned()
-    };
-    match user {
-        Some(user) => {
-            let user_id = user.id.clone();
-
-            // authenticate nitram session
-            anonym_session.auth(&user_id, expires_at).await;
-
-            Ok(user_id)
-        }
-        None => {
-            tracing::debug!("Invalid token. Not surprising since the DB is not persistent. Front-end should clear the token.");
-            Err(MethodError::NotAuthenticated)
-        }
-    }
-}
-
 #[derive(Clone, Deserialize, Serialize, TS)]
 struct MessagesOutput {
     messages: Vec<String>,
@@ -230,6 +219,23 @@ nitram_handler!(
     channel: String
 );
 
+async fn history_handler(
+    resource: NitramResource,
+    _session: WSSessionAuthedResource,
+    params: MessagesParams,
+) -> MethodResult<NitramStream<String>> {
+    let messages = resource.db.messages.lock().await;
+    let history = messages.get(&params.channel).cloned().unwrap_or_default();
+    Ok(Box::pin(futures_util::stream::iter(
+        history.into_iter().map(Ok),
+    )))
+}
+nitram_handler!(
+    HistoryAPI,     // Method name
+    MessagesParams, // Params type
+    String          // Item type
+);
+
 async fn get_user_handler(resource: NitramResource, params: IdParams) -> MethodResult<User> {
     let users = resource.db.users.lock().await;
     match users.get(&params.id) {
@@ -269,23 +275,35 @@ async fn main() -> std::io::Result<()> {
     let cb = NitramBuilder::default()
         .set_server_messages_interval(1000)
         .add_resource(resource)
-        .add_public_handler("Authenticate", authenticate_handler)
+        .set_jwt(JwtAuth::from_secret(JWT_SECRET))
         .add_public_handler("GetToken", get_token_handler)
         .add_private_handler("SendMessage", send_message_handler)
         .add_private_handler("GetUser", get_user_handler)
+        .add_private_stream_handler("History", history_handler)
         .add_server_message_handler("Messages", messages_handler);
     let nitram = cb.build();
-    HttpServer::new(move || {
+    let nitram_for_server = nitram.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
-            .app_data(web::Data::new(nitram.clone()))
+            .app_data(web::Data::new(nitram_for_server.clone()))
             .route("/ws", web::get().to(ws::handler))
             .route("/", web::get().to(index))
             .service(actix_files::Files::new("/", "examples/main/web-app/dist"))
     })
+    .disable_signals()
     .bind(("0.0.0.0", 8000))?
-    .run()
-    .await
+    .run();
+
+    // Graceful shutdown: let the clients finish their requests and reconnect
+    let server_handle = server.handle();
+    actix_web::rt::spawn(async move {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+        nitram.shutdown(std::time::Duration::from_secs(10)).await;
+        server_handle.stop(true).await;
+    });
+
+    server.await
 }
 
 // =============================================================================
@@ -293,8 +311,25 @@ async fn main() -> std::io::Result<()> {
 // =============================================================================
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use nitram::params::{ParamError, ParamErrors};
+
     #[test]
     fn test_to_generate_bindings() {
         assert!(true);
     }
+
+    #[test]
+    fn test_send_message_params_validation() {
+        let params = json!({ "message": "hi", "channel": "Not a channel!" });
+        let Err(rpc_router::Error::Handler(e)) = SendMessageParams::into_params(Some(params))
+        else {
+            panic!("expected invalid params");
+        };
+        let ParamErrors(errors) = e.get::<ParamErrors>().unwrap();
+        assert_eq!(
+            errors,
+            &vec![ParamError::new("params.channel", "must match ^[a-z0-9-]+$")]
+        );
+    }
 }
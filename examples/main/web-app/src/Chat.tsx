@@ -1,4 +1,5 @@
 import type { MessagesAPI, SendMessageAPI } from "bindings/API";
+import type { ChatError } from "bindings/ChatError";
 import { NitramErrorCode, type ServerMessageHandler } from "nitram";
 import { createMemo, createSignal, For, onMount } from "solid-js";
 
@@ -41,11 +42,16 @@ function Chat() {
         setMessages({ ...messages, [_channel]: channel_messages });
       })
       .catch((err) => {
+        const chatError = err as ChatError;
         if (
           typeof err === "object" &&
           err.error === NitramErrorCode.DuplicateRequestQueued
         ) {
           input.value = "";
+        } else if (chatError?.code === "empty_message") {
+          input.value = "";
+        } else if (chatError?.code === "message_too_long") {
+          alert(`Messages can be up to ${chatError.data.max} characters long`);
         } else {
           console.error("Error sending message:", err);
         }
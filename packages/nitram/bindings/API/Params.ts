@@ -1,3 +1,5 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
 export type AuthenticateParams = { token: string, };
+
+export type RefreshParams = { token: string, };
@@ -1,4 +1,7 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 import type { AuthenticateParams } from "./Params";
+import type { RefreshParams } from "./Params";
 
 export type AuthenticateAPI = { i: AuthenticateParams, o: string, };
+
+export type RefreshAPI = { i: RefreshParams, o: string, };
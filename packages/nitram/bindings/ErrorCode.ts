@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Serialized, it is the stable `code` of an `ErrorPayload`.
+ */
+export type ErrorCode = "server_error" | "not_found" | "not_authorized" | "not_authenticated" | "session_expired" | "bad_request" | "no_response" | "invalid_json" | "missing_id" | "missing_method" | "invalid_params" | "batch_too_large" | "upgrade_required" | "cancelled" | "timeout" | "invalid_deadline" | "duplicate_id";
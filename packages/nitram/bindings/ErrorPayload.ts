@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { JsonValue } from "./serde_json/JsonValue";
+
+/**
+ * Error response of protocol version 2. `code` is an `ErrorCode`, or the
+ * code of an application error (see `error::AppError`).
+ */
+export type ErrorPayload = { code: string, message: string, data?: JsonValue, };
@@ -3,3 +3,13 @@
 export type EmptyParams = null;
 
 export type IdParams = { id: string, };
+
+/**
+ * A param that failed to deserialize or validate. Bad request responses
+ * carry the list of them as data.
+ */
+export type ParamError = { 
+/**
+ * e.g. `params.channel`
+ */
+path: string, message: string, };
@@ -1,5 +1,6 @@
 export enum NitramErrorCode {
   DuplicateRequestQueued = "request already queued",
+  NotConnected = "not connected",
 }
 
 export class NitramError {
@@ -1,4 +1,7 @@
-import type { AuthenticateAPI } from "./bindings/API";
+import type { AuthenticateAPI, RefreshAPI } from "./bindings/API";
+import type { ErrorPayload } from "./bindings/ErrorPayload";
+import type { NitramHello } from "./bindings/NitramHello";
+import type { NitramPartialResponse } from "./bindings/NitramPartialResponse";
 import type { NitramRequest } from "./bindings/NitramRequest";
 import type { NitramResponse } from "./bindings/NitramResponse";
 import type { NitramServerMessage } from "./bindings/NitramServerMessage";
@@ -7,6 +10,12 @@ import { NitramError, NitramErrorCode } from "./error";
 import { objectHash } from "./hash";
 
 export { NitramError, NitramErrorCode };
+export type { ErrorPayload, NitramHello, NitramPartialResponse };
+
+// Protocol version 2: errors are `ErrorPayload` objects
+const PROTOCOL_VERSION = "2";
+// `max_batch_size` of the server until its hello is received
+const DEFAULT_MAX_BATCH_SIZE = 32;
 
 // biome-ignore lint/suspicious/noExplicitAny: see below what didn't work
 type Handler = (x: any) => void;
@@ -51,14 +60,19 @@ function randomId(length = 6) {
 export class Server {
   // -- Public
   is_authenticated: string | null = null;
+  // What the server sent when the socket opened: its settings and methods
+  hello: NitramHello | null = null;
 
   // -- Private
   private _stop = false;
   private url: string;
+  private resumeToken: string | null = null;
+  private reconnectOnClose = false;
   private lastState: number = WebSocket.CLOSED;
   private ws: WebSocket;
   private handlers: HandlerByRequestId = new Map();
   private errorHandlers: Map<string, (data: JsonValue) => void> = new Map();
+  private partialHandlers: HandlerByRequestId = new Map();
   private eventHandlers: Map<string, EventHandler[]> = new Map();
   private serverMessageHandlers: Map<string, ServerMessageHandler[]> =
     new Map();
@@ -71,7 +85,7 @@ export class Server {
    */
   constructor(url: string) {
     this.url = url;
-    this.ws = new WebSocket(url);
+    this.ws = new WebSocket(this.connectUrl(), this.protocols());
     this.init();
     this.check_connection();
   }
@@ -87,6 +101,79 @@ export class Server {
       // - server messages
       const serverMessageData = data as NitramServerMessage;
 
+      // -- first message of the socket
+      if (serverMessageData.topic === "nitram_hello") {
+        this.hello = serverMessageData.payload as NitramHello;
+        console.log("<-- hello", this.hello.ws_session_id);
+        this.triggerEvent("(~ hello ~)", serverMessageData.payload);
+        if (this.hello.user_id !== null) {
+          // Authenticated during the handshake, or resumed
+          console.log("^_^ Authenticated", this.hello.user_id);
+          this.is_authenticated = this.hello.user_id;
+          this.triggerEvent("auth", true);
+          this.flushQueue();
+        } else if (this.resumeToken === null) {
+          // Otherwise, after a reconnect, wait for the resume token message
+          // to know if the previous session was resumed
+          this.authFromStorage().then(() => this.flushQueue());
+        }
+        return;
+      }
+
+      // -- the server doesn't speak PROTOCOL_VERSION, every request fails
+      // until the client is updated
+      if (serverMessageData.topic === "nitram_upgrade_required") {
+        console.error("<-- upgrade required", serverMessageData.payload);
+        this.triggerEvent("(~ upgrade required ~)", serverMessageData.payload);
+        return;
+      }
+
+      // -- the session expired and was downgraded to anonymous
+      if (serverMessageData.topic === "nitram_session_expired") {
+        console.log("<-- session expired");
+        this.is_authenticated = null;
+        this.triggerEvent("auth", false);
+        this.triggerEvent("(~ session expired ~)", null);
+        return;
+      }
+
+      // -- the session expires soon, the app can get a new token and
+      // `refresh()` it
+      if (serverMessageData.topic === "nitram_session_expiring") {
+        console.log("<-- session expiring", serverMessageData.payload);
+        this.triggerEvent("(~ session expiring ~)", serverMessageData.payload);
+        return;
+      }
+
+      // -- the server is shutting down, reconnect as soon as it closes the
+      // socket
+      if (serverMessageData.topic === "nitram_shutdown") {
+        console.log("<-- server shutting down");
+        this.reconnectOnClose = true;
+        this.triggerEvent("(~ shutdown ~)", serverMessageData.payload);
+        return;
+      }
+
+      // -- token to get this session back after a reconnect
+      if (serverMessageData.topic === "nitram_resume_token") {
+        const { token, resumed } = serverMessageData.payload as {
+          token: string;
+          resumed: boolean;
+        };
+        const reconnected = this.resumeToken !== null;
+        this.resumeToken = token;
+        if (reconnected && this.hello?.user_id === null) {
+          if (resumed) {
+            console.log("^_^ Session resumed");
+            this.triggerEvent("auth", this.is_authenticated !== null);
+            this.flushQueue();
+          } else {
+            this.authFromStorage().then(() => this.flushQueue());
+          }
+        }
+        return;
+      }
+
       // -- find registered server message handlers
       const handlers = this.serverMessageHandlers.get(serverMessageData.topic);
       if (handlers) {
@@ -98,6 +185,12 @@ export class Server {
         // -- unhandled server message
         console.log("<-- server msg unhandled: ", serverMessageData.topic);
       }
+    } else if (typeof data === "object" && Object.hasOwn(data, "partial")) {
+      // - items of stream handlers
+      const partialData = data as unknown as NitramPartialResponse;
+      const handler = this.partialHandlers.get(partialData.id);
+      if (handler) handler(partialData.partial);
+      else console.warn("!!! Unhandled partial", partialData);
     } else {
       // - message responses
       if (
@@ -136,25 +229,62 @@ export class Server {
       }
     };
 
+    this.ws.onclose = () => {
+      if (!this.reconnectOnClose) return;
+      this.reconnectOnClose = false;
+      // spread the reconnects of all clients over a second
+      setTimeout(() => this.reconnect(), Math.random() * 1000);
+    };
+
     this.ws.onopen = () => {
+      // The queue is sent once the hello of this connection is received and
+      // the session is authenticated again, see `flushQueue`
       console.log("^_^ Connected to server");
+    };
+  }
 
-      // Try to authenticate user
-      const token = localStorage.getItem("token");
-      if (token) {
-        this.auth(token);
-      } else {
-        this.triggerEvent("auth", false);
-      }
-
-      // Send queued requests
-      this.queue.forEach((req) => {
-        this.request({
-          method: req.method,
-          params: req.params,
-        }).then(req.resolve, req.reject);
+  // Sends the queued requests, in batches the server accepts
+  private flushQueue() {
+    const queue = this.queue;
+    this.queue = [];
+    const size = this.hello?.max_batch_size ?? DEFAULT_MAX_BATCH_SIZE;
+    for (let start = 0; start < queue.length; start += size) {
+      const chunk = queue.slice(start, start + size);
+      this.batch(chunk).forEach((promise, i) => {
+        promise.then(chunk[i].resolve, chunk[i].reject);
       });
-    };
+    }
+  }
+
+  // Resolves once the stored token is accepted or rejected
+  private async authFromStorage() {
+    const token = localStorage.getItem("token");
+    if (token) {
+      await this.auth(token);
+    } else {
+      this.triggerEvent("auth", false);
+    }
+  }
+
+  private connectUrl() {
+    const separator = this.url.includes("?") ? "&" : "?";
+    const url = `${this.url}${separator}nitram_protocol=${PROTOCOL_VERSION}`;
+    if (this.resumeToken === null) return url;
+    return `${url}&nitram_resume=${encodeURIComponent(this.resumeToken)}`;
+  }
+
+  // The stored token is offered as a subprotocol, so the server can
+  // authenticate the socket during the handshake
+  private protocols() {
+    const token = localStorage.getItem("token");
+    if (!token) return undefined;
+    return ["nitram.json", `nitram.token.${token}`];
+  }
+
+  private reconnect() {
+    if (this._stop || this.ws.readyState !== WebSocket.CLOSED) return;
+    this.ws = new WebSocket(this.connectUrl(), this.protocols());
+    this.init();
   }
 
   private check_connection() {
@@ -166,7 +296,7 @@ export class Server {
     if (this.ws.readyState === WebSocket.CLOSED) {
       // retry to reconnect in 5 seconds
       setTimeout(() => {
-        this.ws = new WebSocket(this.url);
+        this.reconnect();
         if (this._stop) return;
         setTimeout(() => this.check_connection(), 5000);
       }, 5000);
@@ -220,10 +350,33 @@ export class Server {
     );
   }
 
+  // Extends the session with a new token, keeping its store and topic
+  // registrations
+  async refresh(token: string): Promise<boolean> {
+    return this.request<RefreshAPI>({
+      method: "Refresh",
+      params: { token },
+    }).then(
+      () => {
+        localStorage.setItem("token", token);
+        return true;
+      },
+      (e) => {
+        console.error(e);
+        return false;
+      },
+    );
+  }
+
   logout() {
     this.is_authenticated = null;
     this.triggerEvent("auth", false);
     localStorage.removeItem("token");
+    if (this.ws.readyState === WebSocket.OPEN) {
+      this.request({ method: "nitram_logout", params: null }).catch((e) =>
+        console.error(e),
+      );
+    }
   }
 
   // ---------------------------------------------------------------------------
@@ -294,42 +447,124 @@ export class Server {
 
   // ---------------------------------------------------------------------------
   // -- Request
-  async request<T extends { i: JsonValue; o: JsonValue }>(req: {
-    method: string;
-    params: T["i"];
-  }) {
+
+  // Resolves with the response to the request sent with `request_id`
+  private async response<T extends JsonValue>(
+    request_id: string,
+    method: string,
+  ) {
+    const promise = new Promise<T>((resolve, reject) => {
+      this.registerHandler(
+        request_id,
+        (response: T) => {
+          console.log("===", method, response);
+          resolve(response);
+        },
+        (error: ErrorPayload) => {
+          console.error("===", method, error);
+          if (error?.code === "not_authenticated") {
+            this.triggerEvent("(~ not authenticated ~)", null);
+          }
+          if (error?.code === "session_expired") {
+            this.is_authenticated = null;
+            this.triggerEvent("auth", false);
+            this.triggerEvent("(~ session expired ~)", null);
+          }
+          reject(error);
+        },
+      );
+    });
+    try {
+      const res = await promise;
+      return res;
+    } finally {
+      this.unregisterHandler(request_id);
+    }
+  }
+
+  // Rejects with the reason of `signal` when it aborts, and asks the server
+  // to cancel the request
+  private abortable<T>(
+    promise: Promise<T>,
+    request_id: string,
+    signal?: AbortSignal,
+  ): Promise<T> {
+    if (!signal) return promise;
+    return new Promise<T>((resolve, reject) => {
+      const onAbort = () => {
+        this.cancel(request_id);
+        this.unregisterHandler(request_id);
+        this.partialHandlers.delete(request_id);
+        reject(signal.reason);
+      };
+      if (signal.aborted) {
+        onAbort();
+        return;
+      }
+      signal.addEventListener("abort", onAbort, { once: true });
+      promise
+        .then(resolve, reject)
+        .finally(() => signal.removeEventListener("abort", onAbort));
+    });
+  }
+
+  // Asks the server to stop handling a request, it responds to it with a
+  // `cancelled` error
+  private cancel(request_id: string) {
+    if (this.ws.readyState !== WebSocket.OPEN) return;
+    this.request({ method: "nitram_cancel", params: { id: request_id } }).catch(
+      (e) => console.error(e),
+    );
+  }
+
+  // Sends the requests in one frame, the server answers with one frame too.
+  // Returns a promise per request. When the connection is closed, they are
+  // queued like with `request`.
+  batch(
+    reqs: { method: string; params: JsonValue; deadline?: Date }[],
+  ): Promise<JsonValue>[] {
+    if (this.ws.readyState !== WebSocket.OPEN) {
+      return reqs.map((req) => this.request(req));
+    }
+    const payloads: NitramRequest[] = reqs.map((req) => ({
+      id: randomId(),
+      method: req.method,
+      params: req.params,
+      deadline: req.deadline?.toISOString(),
+    }));
+    const promises = payloads.map((payload) =>
+      this.response(payload.id, payload.method),
+    );
+    if (payloads.length > 0) {
+      this.ws.send(JSON.stringify(payloads));
+    }
+    return promises;
+  }
+
+  // Aborting `signal` rejects the promise and cancels the request on the
+  // server, or drops it from the queue. Past `deadline` the server responds
+  // with a `timeout` error
+  async request<T extends { i: JsonValue; o: JsonValue }>(
+    req: {
+      method: string;
+      params: T["i"];
+      deadline?: Date;
+    },
+    signal?: AbortSignal,
+  ) {
     const request_id = randomId();
     const payload: NitramRequest = {
       id: request_id,
       method: req.method,
       params: req.params,
+      deadline: req.deadline?.toISOString(),
     };
 
     if (this.ws.readyState === WebSocket.OPEN) {
       // Connection open -------------------------------------------------------
-      const promise = new Promise<T["o"]>((resolve, reject) => {
-        this.registerHandler(
-          request_id,
-          (response: T["o"]) => {
-            console.log("===", req.method, response);
-            resolve(response);
-          },
-          (error) => {
-            console.error("===", req.method, error);
-            if (error === "(~ not authenticated ~)") {
-              this.triggerEvent("(~ not authenticated ~)", null);
-            }
-            reject(error);
-          },
-        );
-      });
+      const promise = this.response<T["o"]>(request_id, req.method);
       this.ws.send(JSON.stringify(payload));
-      try {
-        const res = await promise;
-        return res;
-      } finally {
-        this.unregisterHandler(request_id);
-      }
+      return this.abortable(promise, request_id, signal);
     } else {
       // Connection closed -----------------------------------------------------
       const hash = objectHash({
@@ -345,6 +580,7 @@ export class Server {
           hash,
           method: payload.method,
           params: payload.params,
+          deadline: payload.deadline,
           resolve: (_) => {},
           reject: () => {},
         };
@@ -353,6 +589,14 @@ export class Server {
           item.reject = rej;
         });
         this.queue.push(item);
+        signal?.addEventListener(
+          "abort",
+          () => {
+            this.queue = this.queue.filter((queued) => queued !== item);
+            item.reject(signal.reason);
+          },
+          { once: true },
+        );
         return promise;
       } else {
         // return error telling the caller that an identical request is already
@@ -365,4 +609,35 @@ export class Server {
       }
     }
   }
+
+  // Calls a stream handler: `onItem` gets each item as it arrives, and the
+  // promise resolves with the number of items once the stream ends. Streams
+  // are not queued while the connection is closed. Aborting `signal` cancels
+  // the stream.
+  async stream<T extends { i: JsonValue; o: JsonValue }>(
+    req: { method: string; params: T["i"]; deadline?: Date },
+    onItem: (item: T["o"]) => void,
+    signal?: AbortSignal,
+  ) {
+    if (this.ws.readyState !== WebSocket.OPEN) {
+      return Promise.reject(
+        new NitramError(NitramErrorCode.NotConnected, { method: req.method }),
+      );
+    }
+    const request_id = randomId();
+    const payload: NitramRequest = {
+      id: request_id,
+      method: req.method,
+      params: req.params,
+      deadline: req.deadline?.toISOString(),
+    };
+    this.partialHandlers.set(request_id, onItem);
+    try {
+      const promise = this.response<number>(request_id, req.method);
+      this.ws.send(JSON.stringify(payload));
+      return await this.abortable(promise, request_id, signal);
+    } finally {
+      this.partialHandlers.delete(request_id);
+    }
+  }
 }
@@ -1,10 +1,11 @@
+use actix_web::{http::header, web, HttpRequest};
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use rpc_router::{IntoParams, RpcResource};
 use serde::Deserialize;
 use serde_json::Value;
 use std::sync::Arc;
 use std::{collections::HashMap, fmt};
-use tokio::sync::Mutex;
 use ts_rs::TS;
 use uuid::Uuid;
 
@@ -14,18 +15,29 @@ use crate::{nitram_handler, NitramState};
 #[derive(Clone, RpcResource)]
 pub struct WSSessionAnonymResource {
     pub ws_session_id: Uuid,
-    pub nitram_state: Arc<Mutex<NitramState>>,
+    pub nitram_state: Arc<NitramState>,
 }
 
 impl WSSessionAnonymResource {
-    pub async fn auth(&self, user_id: &str, expires_at: DateTime<Utc>) -> () {
+    pub async fn auth(&self, user_id: &str, expires_at: DateTime<Utc>) {
         let user_session = UserSession {
-            id: self.ws_session_id.clone(),
+            id: self.ws_session_id,
             user_id: user_id.to_string(),
             expires_at,
         };
-        let mut state = self.nitram_state.lock().await;
-        state.auth_ws_session(self.ws_session_id, user_session);
+        self.nitram_state
+            .auth_ws_session(self.ws_session_id, user_session)
+            .await;
+    }
+
+    /// Extends the session of the authenticated user, e.g. with the expiry
+    /// of a new token. Unlike `auth`, it keeps the `Store` and the topic
+    /// registrations. Returns false if the ws session is not authenticated as
+    /// `user_id`, or already expired.
+    pub async fn refresh(&self, user_id: &str, expires_at: DateTime<Utc>) -> bool {
+        self.nitram_state
+            .refresh_ws_session(&self.ws_session_id, user_id, expires_at)
+            .await
     }
 }
 
@@ -34,6 +46,90 @@ pub struct WSSessionAuthedResource {
     pub user_id: String,
 }
 
+/// Authenticates a websocket during the handshake, from its upgrade request,
+/// so it starts authenticated instead of sending `Authenticate` once open.
+/// Set it with `NitramBuilder::set_authenticator`.
+///
+/// ```ignore
+/// struct TokenAuthenticator;
+///
+/// #[async_trait(?Send)]
+/// impl Authenticator for TokenAuthenticator {
+///     async fn authenticate(&self, req: &HttpRequest) -> Option<AuthenticatedUser> {
+///         let token = TokenSource::find(&TokenSource::DEFAULTS, req)?;
+///         verify(&token).await
+///     }
+/// }
+/// ```
+#[async_trait(?Send)]
+pub trait Authenticator: Send + Sync {
+    /// The user of the websocket, `None` to start anonymous.
+    async fn authenticate(&self, req: &HttpRequest) -> Option<AuthenticatedUser>;
+}
+
+/// Who a websocket is authenticated as, see `Authenticator`.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Where the token of a websocket is read from in its upgrade request.
+#[derive(Clone, Copy, Debug)]
+pub enum TokenSource {
+    /// A header, e.g. `Authorization`. A `Bearer ` prefix is stripped
+    Header(&'static str),
+    Cookie(&'static str),
+    /// A query parameter of the websocket url
+    Query(&'static str),
+    /// A subprotocol offered by the client that starts with the prefix,
+    /// e.g. `nitram.token.<token>`. Browsers can't set headers on websockets
+    /// but can offer subprotocols. The client has to offer an encoding
+    /// subprotocol too, e.g. `nitram.json`, for one to be accepted
+    Subprotocol(&'static str),
+}
+
+impl TokenSource {
+    /// The `Authorization` header, the `nitram_token` cookie and query
+    /// parameter, and the `nitram.token.` subprotocol, as sent by the TS
+    /// client.
+    pub const DEFAULTS: [TokenSource; 4] = [
+        TokenSource::Header("authorization"),
+        TokenSource::Cookie("nitram_token"),
+        TokenSource::Query("nitram_token"),
+        TokenSource::Subprotocol("nitram.token."),
+    ];
+
+    pub fn read(&self, req: &HttpRequest) -> Option<String> {
+        let token = match self {
+            TokenSource::Header(name) => {
+                let value = req.headers().get(*name)?.to_str().ok()?;
+                value.strip_prefix("Bearer ").unwrap_or(value).to_string()
+            }
+            TokenSource::Cookie(name) => req.cookie(name)?.value().to_string(),
+            TokenSource::Query(name) => {
+                web::Query::<HashMap<String, String>>::from_query(req.query_string())
+                    .ok()?
+                    .into_inner()
+                    .remove(*name)?
+            }
+            TokenSource::Subprotocol(prefix) => req
+                .headers()
+                .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .find_map(|protocol| protocol.trim().strip_prefix(prefix))?
+                .to_string(),
+        };
+        (!token.is_empty()).then_some(token)
+    }
+
+    /// The first token found, trying the sources in order.
+    pub fn find(sources: &[TokenSource], req: &HttpRequest) -> Option<String> {
+        sources.iter().find_map(|source| source.read(req))
+    }
+}
+
 #[derive(Clone)]
 pub enum NitramSession {
     Anonymous,
@@ -52,6 +148,13 @@ impl NitramSession {
             store: Store::new(),
         }
     }
+
+    pub fn user_id(&self) -> Option<String> {
+        match self {
+            NitramSession::Anonymous => None,
+            NitramSession::Authenticated { user_session, .. } => Some(user_session.user_id.clone()),
+        }
+    }
 }
 
 impl fmt::Debug for NitramSession {
@@ -81,3 +184,11 @@ nitram_handler!(
     // Params
     token: String
 );
+
+nitram_handler!(
+    RefreshAPI,    // Method name
+    RefreshParams, // Params type
+    String,        // Return type
+    // Params
+    token: String
+);
@@ -0,0 +1,682 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::auth::NitramSession;
+use crate::models::{Store, UserSession};
+
+/// Where the ws sessions are kept. Besides the session itself, a backend
+/// keeps the resume token handed to the client and when the websocket of the
+/// session closed, so sessions can be resumed after a reconnect.
+///
+/// The default `refresh`, `register_topic` and `deregister_topic` get the
+/// session, change it and insert it back. They are not atomic: two of them
+/// running at once on the same session can lose a change. Backends that can
+/// change a session in place should override them, like `MemoryBackend`.
+#[async_trait]
+pub trait SessionBackend: Send + Sync {
+    /// Inserts or replaces the session, keeping its resume token.
+    async fn insert(&self, ws_session_id: Uuid, session: NitramSession);
+
+    /// Removes the session together with its resume token.
+    async fn remove(&self, ws_session_id: &Uuid) -> Option<NitramSession>;
+
+    async fn get(&self, ws_session_id: &Uuid) -> Option<NitramSession>;
+
+    async fn len(&self) -> usize;
+
+    async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Ids of the authenticated sessions registered to the topic.
+    async fn subscribers(&self, topic: &str) -> Vec<Uuid>;
+
+    async fn auth(&self, ws_session_id: Uuid, user_session: UserSession) {
+        self.insert(ws_session_id, NitramSession::new_auth(user_session))
+            .await;
+    }
+
+    /// Extends the user session, keeping its store and topic registrations.
+    /// Returns false if the session is not authenticated.
+    async fn refresh(&self, ws_session_id: &Uuid, expires_at: DateTime<Utc>) -> bool {
+        let Some(mut session) = self.get(ws_session_id).await else {
+            return false;
+        };
+        let NitramSession::Authenticated { user_session, .. } = &mut session else {
+            return false;
+        };
+        user_session.expires_at = expires_at;
+        self.insert(*ws_session_id, session).await;
+        true
+    }
+
+    /// Returns false if the session is not authenticated.
+    async fn register_topic(&self, ws_session_id: &Uuid, topic: &str, params: Value) -> bool {
+        let Some(mut session) = self.get(ws_session_id).await else {
+            return false;
+        };
+        let NitramSession::Authenticated {
+            topics_registered, ..
+        } = &mut session
+        else {
+            return false;
+        };
+        topics_registered.insert(topic.to_string(), params);
+        self.insert(*ws_session_id, session).await;
+        true
+    }
+
+    /// Returns false if the session is not authenticated.
+    async fn deregister_topic(&self, ws_session_id: &Uuid, topic: &str) -> bool {
+        let Some(mut session) = self.get(ws_session_id).await else {
+            return false;
+        };
+        let NitramSession::Authenticated {
+            topics_registered, ..
+        } = &mut session
+        else {
+            return false;
+        };
+        topics_registered.remove(topic);
+        self.insert(*ws_session_id, session).await;
+        true
+    }
+
+    async fn store(&self, ws_session_id: &Uuid) -> Option<Store> {
+        match self.get(ws_session_id).await {
+            Some(NitramSession::Authenticated { store, .. }) => Some(store),
+            _ => None,
+        }
+    }
+
+    /// Called after a handler had access to the store of the session.
+    async fn save_store(&self, _ws_session_id: &Uuid, _store: &Store) {}
+
+    // -- Session resumption
+
+    async fn set_resume_token(&self, ws_session_id: &Uuid, token: String);
+
+    async fn resume_token(&self, ws_session_id: &Uuid) -> Option<String>;
+
+    /// Id of the session that was handed the resume token, if it is detached.
+    /// A session still attached to a websocket can't be taken over.
+    async fn find_by_resume_token(&self, token: &str) -> Option<Uuid>;
+
+    /// Marks the session as having no websocket since `at`.
+    async fn detach(&self, ws_session_id: &Uuid, at: DateTime<Utc>);
+
+    /// Ids of the sessions without a websocket since before `at`.
+    async fn detached_before(&self, at: DateTime<Utc>) -> Vec<Uuid>;
+
+    /// Called on shutdown. Drops every session, or writes them for backends
+    /// that keep sessions across restarts.
+    async fn clear(&self);
+}
+
+// =============================================================================
+// In memory
+// =============================================================================
+
+#[derive(Clone)]
+struct Record {
+    session: NitramSession,
+    resume_token: Option<String>,
+    detached_at: Option<DateTime<Utc>>,
+}
+
+type Shard = HashMap<Uuid, Record>;
+
+/// Keeps the sessions in memory. Sessions are lost when the server restarts.
+///
+/// Sessions are spread over shards with a lock each, so operations on
+/// unrelated sessions rarely wait for each other. Locks are never held across
+/// an await.
+pub struct MemoryBackend {
+    shards: Vec<RwLock<Shard>>,
+    /// Session ids by resume token
+    resume_tokens: RwLock<HashMap<String, Uuid>>,
+    /// Ids of the sessions registered to each topic. Updated while the shard
+    /// of the session is locked, so it matches the sessions.
+    topics: RwLock<HashMap<String, HashSet<Uuid>>>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::with_shards(64)
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            resume_tokens: RwLock::new(HashMap::new()),
+            topics: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn shard(&self, ws_session_id: &Uuid) -> &RwLock<Shard> {
+        let index = ws_session_id.as_u128() % self.shards.len() as u128;
+        &self.shards[index as usize]
+    }
+
+    fn read(&self, ws_session_id: &Uuid) -> RwLockReadGuard<'_, Shard> {
+        read(self.shard(ws_session_id))
+    }
+
+    fn write(&self, ws_session_id: &Uuid) -> RwLockWriteGuard<'_, Shard> {
+        self.shard(ws_session_id)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn resume_tokens_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, Uuid>> {
+        self.resume_tokens
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn topics_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, HashSet<Uuid>>> {
+        self.topics.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Moves the session from the topics of `old` to the topics of `new` in
+    /// the index.
+    fn index_topics(
+        &self,
+        ws_session_id: &Uuid,
+        old: Option<&NitramSession>,
+        new: Option<&NitramSession>,
+    ) {
+        let mut topics = self.topics_mut();
+        for topic in registered_topics(old) {
+            unindex_topic(&mut topics, topic, ws_session_id);
+        }
+        for topic in registered_topics(new) {
+            topics
+                .entry(topic.clone())
+                .or_default()
+                .insert(*ws_session_id);
+        }
+    }
+
+    fn find_ids(&self, predicate: impl Fn(&Record) -> bool) -> Vec<Uuid> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                read(shard)
+                    .iter()
+                    .filter(|(_, record)| predicate(record))
+                    .map(|(ws_session_id, _)| *ws_session_id)
+                    .collect::<Vec<Uuid>>()
+            })
+            .collect()
+    }
+
+    fn records(&self) -> Vec<(Uuid, Record)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                read(shard)
+                    .iter()
+                    .map(|(ws_session_id, record)| (*ws_session_id, record.clone()))
+                    .collect::<Vec<(Uuid, Record)>>()
+            })
+            .collect()
+    }
+
+    fn insert_record(&self, ws_session_id: Uuid, record: Record) {
+        if let Some(token) = &record.resume_token {
+            self.resume_tokens_mut()
+                .insert(token.clone(), ws_session_id);
+        }
+        let mut shard = self.write(&ws_session_id);
+        let previous = shard.insert(ws_session_id, record);
+        self.index_topics(
+            &ws_session_id,
+            previous.as_ref().map(|record| &record.session),
+            shard.get(&ws_session_id).map(|record| &record.session),
+        );
+    }
+}
+
+fn read(shard: &RwLock<Shard>) -> RwLockReadGuard<'_, Shard> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn registered_topics(session: Option<&NitramSession>) -> impl Iterator<Item = &String> {
+    session
+        .and_then(|session| match session {
+            NitramSession::Authenticated {
+                topics_registered, ..
+            } => Some(topics_registered.keys()),
+            NitramSession::Anonymous => None,
+        })
+        .into_iter()
+        .flatten()
+}
+
+fn unindex_topic(topics: &mut HashMap<String, HashSet<Uuid>>, topic: &str, ws_session_id: &Uuid) {
+    if let Some(ws_session_ids) = topics.get_mut(topic) {
+        ws_session_ids.remove(ws_session_id);
+        if ws_session_ids.is_empty() {
+            topics.remove(topic);
+        }
+    }
+}
+
+#[async_trait]
+impl SessionBackend for MemoryBackend {
+    async fn insert(&self, ws_session_id: Uuid, session: NitramSession) {
+        let mut shard = self.write(&ws_session_id);
+        match shard.get_mut(&ws_session_id) {
+            Some(record) => {
+                let previous = std::mem::replace(&mut record.session, session);
+                self.index_topics(&ws_session_id, Some(&previous), Some(&record.session));
+            }
+            None => {
+                self.index_topics(&ws_session_id, None, Some(&session));
+                shard.insert(
+                    ws_session_id,
+                    Record {
+                        session,
+                        resume_token: None,
+                        detached_at: None,
+                    },
+                );
+            }
+        }
+    }
+
+    async fn remove(&self, ws_session_id: &Uuid) -> Option<NitramSession> {
+        let mut shard = self.write(ws_session_id);
+        let record = shard.remove(ws_session_id)?;
+        self.index_topics(ws_session_id, Some(&record.session), None);
+        drop(shard);
+        if let Some(token) = &record.resume_token {
+            self.resume_tokens_mut().remove(token);
+        }
+        Some(record.session)
+    }
+
+    async fn get(&self, ws_session_id: &Uuid) -> Option<NitramSession> {
+        self.read(ws_session_id)
+            .get(ws_session_id)
+            .map(|record| record.session.clone())
+    }
+
+    async fn len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    async fn subscribers(&self, topic: &str) -> Vec<Uuid> {
+        self.topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(topic)
+            .map(|ws_session_ids| ws_session_ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    async fn refresh(&self, ws_session_id: &Uuid, expires_at: DateTime<Utc>) -> bool {
+        let mut shard = self.write(ws_session_id);
+        match shard
+            .get_mut(ws_session_id)
+            .map(|record| &mut record.session)
+        {
+            Some(NitramSession::Authenticated { user_session, .. }) => {
+                user_session.expires_at = expires_at;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn register_topic(&self, ws_session_id: &Uuid, topic: &str, params: Value) -> bool {
+        let mut shard = self.write(ws_session_id);
+        match shard
+            .get_mut(ws_session_id)
+            .map(|record| &mut record.session)
+        {
+            Some(NitramSession::Authenticated {
+                topics_registered, ..
+            }) => {
+                topics_registered.insert(topic.to_string(), params);
+                self.topics_mut()
+                    .entry(topic.to_string())
+                    .or_default()
+                    .insert(*ws_session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn deregister_topic(&self, ws_session_id: &Uuid, topic: &str) -> bool {
+        let mut shard = self.write(ws_session_id);
+        match shard
+            .get_mut(ws_session_id)
+            .map(|record| &mut record.session)
+        {
+            Some(NitramSession::Authenticated {
+                topics_registered, ..
+            }) => {
+                topics_registered.remove(topic);
+                unindex_topic(&mut self.topics_mut(), topic, ws_session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn set_resume_token(&self, ws_session_id: &Uuid, token: String) {
+        let previous = match self.write(ws_session_id).get_mut(ws_session_id) {
+            Some(record) => record.resume_token.replace(token.clone()),
+            None => return,
+        };
+        let mut resume_tokens = self.resume_tokens_mut();
+        if let Some(previous) = previous {
+            resume_tokens.remove(&previous);
+        }
+        resume_tokens.insert(token, *ws_session_id);
+    }
+
+    async fn resume_token(&self, ws_session_id: &Uuid) -> Option<String> {
+        self.read(ws_session_id)
+            .get(ws_session_id)
+            .and_then(|record| record.resume_token.clone())
+    }
+
+    async fn find_by_resume_token(&self, token: &str) -> Option<Uuid> {
+        let ws_session_id = *self
+            .resume_tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)?;
+        let detached = self
+            .read(&ws_session_id)
+            .get(&ws_session_id)
+            .is_some_and(|record| record.detached_at.is_some());
+        detached.then_some(ws_session_id)
+    }
+
+    async fn detach(&self, ws_session_id: &Uuid, at: DateTime<Utc>) {
+        if let Some(record) = self.write(ws_session_id).get_mut(ws_session_id) {
+            record.detached_at.get_or_insert(at);
+        }
+    }
+
+    async fn detached_before(&self, at: DateTime<Utc>) -> Vec<Uuid> {
+        self.find_ids(|record| {
+            record
+                .detached_at
+                .is_some_and(|detached_at| detached_at < at)
+        })
+    }
+
+    async fn clear(&self) {
+        for shard in &self.shards {
+            shard
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        self.resume_tokens_mut().clear();
+        self.topics_mut().clear();
+    }
+}
+
+// =============================================================================
+// File
+// =============================================================================
+
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+    user_session: Option<UserSession>,
+    topics_registered: HashMap<String, Value>,
+    store: HashMap<String, Value>,
+    resume_token: Option<String>,
+    detached_at: Option<DateTime<Utc>>,
+}
+
+/// Keeps the sessions in memory and writes them to a JSON file after they
+/// change, so they survive a restart. Sessions loaded from the file have no
+/// websocket, and can be resumed within the resume grace period.
+///
+/// Changes are written in the background, `write_delay` after the first one,
+/// so a burst of changes is written once. The file is replaced atomically, a
+/// crash mid-write leaves the previous one. Pending changes are written on
+/// shutdown, see `SessionBackend::clear`.
+pub struct FileBackend {
+    inner: Arc<FileInner>,
+    write_delay: Duration,
+}
+
+struct FileInner {
+    path: PathBuf,
+    memory: MemoryBackend,
+    /// A write is scheduled that will include every change made so far
+    write_scheduled: AtomicBool,
+    write_lock: Mutex<()>,
+}
+
+impl FileBackend {
+    /// Loads the sessions from `path` if the file exists.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let memory = MemoryBackend::new();
+        if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            let file_records: BTreeMap<Uuid, FileRecord> = serde_json::from_str(&content)?;
+            let now = Utc::now();
+            for (ws_session_id, file_record) in file_records {
+                let session = match file_record.user_session {
+                    Some(user_session) => NitramSession::Authenticated {
+                        user_session,
+                        topics_registered: file_record.topics_registered,
+                        store: Store::from(file_record.store),
+                    },
+                    None => NitramSession::Anonymous,
+                };
+                memory.insert_record(
+                    ws_session_id,
+                    Record {
+                        session,
+                        resume_token: file_record.resume_token,
+                        detached_at: Some(file_record.detached_at.unwrap_or(now)),
+                    },
+                );
+            }
+            tracing::info!(
+                path = path.display().to_string(),
+                count = memory.records().len(),
+                "Loaded sessions"
+            );
+        }
+        Ok(Self {
+            inner: Arc::new(FileInner {
+                path,
+                memory,
+                write_scheduled: AtomicBool::new(false),
+                write_lock: Mutex::new(()),
+            }),
+            write_delay: Duration::from_millis(100),
+        })
+    }
+
+    /// How long after a change the file is written (default = 100ms). The
+    /// changes made in the meantime are written with it.
+    pub fn set_write_delay(mut self, write_delay_in_millis: u64) -> Self {
+        self.write_delay = Duration::from_millis(write_delay_in_millis);
+        self
+    }
+
+    fn memory(&self) -> &MemoryBackend {
+        &self.inner.memory
+    }
+
+    /// Schedules a write of the sessions, unless one is scheduled already.
+    fn persist(&self) {
+        if self.inner.write_scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let inner = self.inner.clone();
+        let write_delay = self.write_delay;
+        tokio::spawn(async move {
+            tokio::time::sleep(write_delay).await;
+            inner.write().await;
+        });
+    }
+
+    /// Writes the pending changes right away.
+    pub async fn flush(&self) {
+        if self.inner.write_scheduled.load(Ordering::Acquire) {
+            self.inner.write().await;
+        }
+    }
+}
+
+impl FileInner {
+    async fn write(&self) {
+        let _write_lock = self.write_lock.lock().await;
+        // Changes made from now on schedule another write
+        if !self.write_scheduled.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let records = self.memory.records();
+        let mut file_records = BTreeMap::new();
+        for (ws_session_id, record) in records {
+            let file_record = match record.session {
+                NitramSession::Anonymous => FileRecord {
+                    user_session: None,
+                    topics_registered: HashMap::new(),
+                    store: HashMap::new(),
+                    resume_token: record.resume_token,
+                    detached_at: record.detached_at,
+                },
+                NitramSession::Authenticated {
+                    user_session,
+                    topics_registered,
+                    store,
+                } => FileRecord {
+                    user_session: Some(user_session),
+                    topics_registered,
+                    store: store.kv.lock().await.clone(),
+                    resume_token: record.resume_token,
+                    detached_at: record.detached_at,
+                },
+            };
+            file_records.insert(ws_session_id, file_record);
+        }
+        let result = match serde_json::to_vec(&file_records) {
+            Ok(content) => {
+                let tmp_path = self.path.with_extension("tmp");
+                match tokio::fs::write(&tmp_path, content).await {
+                    Ok(()) => tokio::fs::rename(&tmp_path, &self.path).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            tracing::error!(
+                path = self.path.display().to_string(),
+                "Error writing sessions: {}",
+                e
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl SessionBackend for FileBackend {
+    async fn insert(&self, ws_session_id: Uuid, session: NitramSession) {
+        self.memory().insert(ws_session_id, session).await;
+        self.persist();
+    }
+
+    async fn remove(&self, ws_session_id: &Uuid) -> Option<NitramSession> {
+        let removed = self.memory().remove(ws_session_id).await;
+        self.persist();
+        removed
+    }
+
+    async fn get(&self, ws_session_id: &Uuid) -> Option<NitramSession> {
+        self.memory().get(ws_session_id).await
+    }
+
+    async fn len(&self) -> usize {
+        self.memory().len().await
+    }
+
+    async fn subscribers(&self, topic: &str) -> Vec<Uuid> {
+        self.memory().subscribers(topic).await
+    }
+
+    async fn refresh(&self, ws_session_id: &Uuid, expires_at: DateTime<Utc>) -> bool {
+        let refreshed = self.memory().refresh(ws_session_id, expires_at).await;
+        self.persist();
+        refreshed
+    }
+
+    async fn register_topic(&self, ws_session_id: &Uuid, topic: &str, params: Value) -> bool {
+        let registered = self
+            .memory()
+            .register_topic(ws_session_id, topic, params)
+            .await;
+        self.persist();
+        registered
+    }
+
+    async fn deregister_topic(&self, ws_session_id: &Uuid, topic: &str) -> bool {
+        let deregistered = self.memory().deregister_topic(ws_session_id, topic).await;
+        self.persist();
+        deregistered
+    }
+
+    async fn save_store(&self, _ws_session_id: &Uuid, _store: &Store) {
+        self.persist();
+    }
+
+    async fn set_resume_token(&self, ws_session_id: &Uuid, token: String) {
+        self.memory().set_resume_token(ws_session_id, token).await;
+        self.persist();
+    }
+
+    async fn resume_token(&self, ws_session_id: &Uuid) -> Option<String> {
+        self.memory().resume_token(ws_session_id).await
+    }
+
+    async fn find_by_resume_token(&self, token: &str) -> Option<Uuid> {
+        self.memory().find_by_resume_token(token).await
+    }
+
+    async fn detach(&self, ws_session_id: &Uuid, at: DateTime<Utc>) {
+        self.memory().detach(ws_session_id, at).await;
+        self.persist();
+    }
+
+    async fn detached_before(&self, at: DateTime<Utc>) -> Vec<Uuid> {
+        self.memory().detached_before(at).await
+    }
+
+    /// Keeps the sessions, and writes the pending changes.
+    async fn clear(&self) {
+        self.flush().await;
+    }
+}
@@ -1,7 +1,20 @@
-use rpc_router::{FromResources, Handler, RouterBuilder};
+use rpc_router::{FromResources, Handler, ResourcesBuilder, RouterBuilder};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::Nitram;
+use crate::auth::Authenticator;
+use crate::backend::{MemoryBackend, SessionBackend};
+use crate::hooks::{hook, Hook, HookContext, Hooks};
+use crate::messages::{BatchExecution, ProtocolVersion};
+use crate::stream::{self, StreamHandler, StreamHandlers};
+use crate::{Nitram, NitramState};
 
+#[derive(Default)]
 pub struct NitramBuilder {
     rpc_router_builder_public: RouterBuilder,
     rpc_router_builder_private: RouterBuilder,
@@ -9,35 +22,147 @@ pub struct NitramBuilder {
     registered_public_handlers: Vec<String>,
     registered_private_handlers: Vec<String>,
     registered_server_messages_handlers: Vec<String>,
+    stream_handlers: StreamHandlers,
+    session_backend: Option<Arc<dyn SessionBackend>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    resources: ResourcesBuilder,
+    on_connect: Vec<Hook>,
+    on_auth: Vec<Hook>,
+    on_disconnect: Vec<Hook>,
     ping_interval_in_seconds: Option<u64>,
     server_messages_interval_in_millis: Option<u64>,
+    server_messages_polling: Option<bool>,
+    session_resume_grace_period_in_seconds: Option<u64>,
+    session_expiring_warning_in_seconds: Option<u64>,
+    max_concurrent_requests: Option<usize>,
+    max_pending_requests: Option<usize>,
+    default_protocol_version: Option<ProtocolVersion>,
+    min_protocol_version: Option<ProtocolVersion>,
+    batch_execution: Option<BatchExecution>,
+    max_batch_size: Option<usize>,
+    request_timeout_in_millis: Option<u64>,
+    method_timeouts_in_millis: HashMap<String, u64>,
     timeout_in_seconds: Option<u64>,
     max_frame_size: Option<usize>,
 }
 
-impl Default for NitramBuilder {
-    fn default() -> Self {
-        NitramBuilder {
-            rpc_router_builder_public: RouterBuilder::default(),
-            rpc_router_builder_private: RouterBuilder::default(),
-            rpc_router_builder_server_messages: RouterBuilder::default(),
-            registered_public_handlers: vec![],
-            registered_private_handlers: vec![],
-            registered_server_messages_handlers: vec![],
-            ping_interval_in_seconds: None,
-            server_messages_interval_in_millis: None,
-            timeout_in_seconds: None,
-            max_frame_size: None,
-        }
-    }
-}
-
 impl NitramBuilder {
     pub fn set_server_messages_interval(mut self, interval_in_millis: u64) -> Self {
         self.server_messages_interval_in_millis = Some(interval_in_millis);
         self
     }
 
+    /// Server messages are pushed to the client as soon as they are
+    /// published with `Nitram::publish`. Polling the server message handlers
+    /// every `server_messages_interval` is kept as a fallback, and can be
+    /// disabled when all topics are published.
+    pub fn set_server_messages_polling(mut self, enabled: bool) -> Self {
+        self.server_messages_polling = Some(enabled);
+        self
+    }
+
+    /// How long the session of a closed websocket is kept, so a reconnecting
+    /// client can resume it with its resume token. Set to 0 to disable
+    /// session resumption.
+    pub fn set_session_resume_grace_period(mut self, grace_period_in_seconds: u64) -> Self {
+        self.session_resume_grace_period_in_seconds = Some(grace_period_in_seconds);
+        self
+    }
+
+    /// How long before `UserSession::expires_at` the client gets a
+    /// `nitram_session_expiring` server message (default = 60), so it can
+    /// refresh its session without reconnecting. Set to 0 to disable.
+    pub fn set_session_expiring_warning(mut self, warning_in_seconds: u64) -> Self {
+        self.session_expiring_warning_in_seconds = Some(warning_in_seconds);
+        self
+    }
+
+    /// How many requests of one websocket are handled at the same time.
+    /// Responses are sent as they complete, so they can arrive in a different
+    /// order than the requests. Set to 1 to handle requests one by one.
+    pub fn set_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = Some(max_concurrent_requests);
+        self
+    }
+
+    /// How many requests of one websocket can wait for one of the
+    /// `max_concurrent_requests` (default = 64). The websocket is not read
+    /// further until one of them is handled.
+    pub fn set_max_pending_requests(mut self, max_pending_requests: usize) -> Self {
+        self.max_pending_requests = Some(max_pending_requests);
+        self
+    }
+
+    /// Protocol version of the websockets that don't ask for one with the
+    /// `nitram_protocol` query parameter. Defaults to `ProtocolVersion::V1`,
+    /// so older clients keep getting `(~ message ~)` error strings.
+    pub fn set_default_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.default_protocol_version = Some(version);
+        self
+    }
+
+    /// Oldest protocol version still served (default = 1). Clients asking for
+    /// an older version, or for one the server doesn't know yet, get an
+    /// `upgrade_required` error for every request. JSON-RPC is always served.
+    pub fn set_min_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.min_protocol_version = Some(version);
+        self
+    }
+
+    /// How the requests of a batch are handled. Defaults to
+    /// `BatchExecution::Parallel`.
+    pub fn set_batch_execution(mut self, execution: BatchExecution) -> Self {
+        self.batch_execution = Some(execution);
+        self
+    }
+
+    /// Most requests in a batch (default = 32). Every request of a larger
+    /// batch is answered with `(~ batch too large ~)`.
+    pub fn set_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    /// How long a request can take before it responds with `(~ timeout ~)`
+    /// and its handler future is dropped. Defaults to 0, no timeout. A client
+    /// can ask for a shorter one with the `deadline` of the request.
+    pub fn set_request_timeout(mut self, timeout_in_millis: u64) -> Self {
+        self.request_timeout_in_millis = Some(timeout_in_millis);
+        self
+    }
+
+    /// `set_request_timeout` for one method, e.g. a slow report or a long
+    /// stream. 0 disables the timeout of the method.
+    pub fn set_method_timeout(mut self, method: &'static str, timeout_in_millis: u64) -> Self {
+        self.method_timeouts_in_millis
+            .insert(method.to_string(), timeout_in_millis);
+        self
+    }
+
+    /// Where sessions are kept. Defaults to `MemoryBackend`.
+    pub fn set_session_backend(mut self, backend: impl SessionBackend + 'static) -> Self {
+        self.session_backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Authenticates the websockets during the handshake, see
+    /// `Authenticator`.
+    pub fn set_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Authenticates with JWTs: during the handshake, and with the
+    /// `Authenticate` and `Refresh` handlers it registers. Also adds the
+    /// `JwtAuth` as a resource.
+    #[cfg(feature = "jwt")]
+    pub fn set_jwt(self, jwt: crate::jwt::JwtAuth) -> Self {
+        self.set_authenticator(jwt.clone())
+            .add_resource(jwt)
+            .add_public_handler("Authenticate", crate::jwt::authenticate_handler)
+            .add_public_handler("Refresh", crate::jwt::refresh_handler)
+    }
+
     pub fn add_resource(
         mut self,
         resource: impl FromResources + Clone + Send + Sync + 'static,
@@ -51,6 +176,40 @@ impl NitramBuilder {
         self.rpc_router_builder_server_messages = self
             .rpc_router_builder_server_messages
             .append_resource(resource.clone());
+        self.resources.append_mut(resource);
+        self
+    }
+
+    /// Runs when a websocket connects. `user_id` is set when the connection
+    /// resumed an authenticated session.
+    pub fn on_connect<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(HookContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connect.push(hook(f));
+        self
+    }
+
+    /// Runs when a ws session is authenticated with
+    /// `WSSessionAnonymResource::auth`.
+    pub fn on_auth<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(HookContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_auth.push(hook(f));
+        self
+    }
+
+    /// Runs when a websocket is closed and its session removed with
+    /// `Nitram::remove`. `user_id` is set if the session was authenticated.
+    pub fn on_disconnect<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(HookContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_disconnect.push(hook(f));
         self
     }
 
@@ -82,6 +241,32 @@ impl NitramBuilder {
         self
     }
 
+    /// Registers a handler returning a stream, see `stream::NitramStream`.
+    /// Each item is sent as a partial response, and the response to the
+    /// request is the number of items once the stream ends.
+    pub fn add_public_stream_handler<H, T, P>(mut self, name: &'static str, handler: H) -> Self
+    where
+        H: StreamHandler<T, P>,
+    {
+        self.registered_public_handlers.push(name.to_string());
+        self.stream_handlers
+            .public
+            .insert(name.to_string(), stream::boxed(handler));
+        self
+    }
+
+    /// `add_public_stream_handler` for authenticated ws sessions.
+    pub fn add_private_stream_handler<H, T, P>(mut self, name: &'static str, handler: H) -> Self
+    where
+        H: StreamHandler<T, P>,
+    {
+        self.registered_private_handlers.push(name.to_string());
+        self.stream_handlers
+            .private
+            .insert(name.to_string(), stream::boxed(handler));
+        self
+    }
+
     pub fn add_server_message_handler<H, T, P, R>(mut self, name: &'static str, handler: H) -> Self
     where
         H: Handler<T, P, R> + Clone + Send + Sync + 'static,
@@ -97,7 +282,7 @@ impl NitramBuilder {
         self
     }
 
-    pub fn build(self) -> Nitram {
+    pub fn build(mut self) -> Nitram {
         tracing::debug!(
             "Registered public handlers: {:?}",
             self.registered_public_handlers
@@ -110,17 +295,57 @@ impl NitramBuilder {
             "Registered server message handlers: {:?}",
             self.registered_server_messages_handlers
         );
-        Nitram::new(
-            self.rpc_router_builder_public.build(),
-            self.rpc_router_builder_private.build(),
-            self.rpc_router_builder_server_messages.build(),
-            self.registered_public_handlers,
-            self.registered_private_handlers,
-            self.registered_server_messages_handlers,
-            self.ping_interval_in_seconds,
-            self.server_messages_interval_in_millis,
-            self.timeout_in_seconds,
-            self.max_frame_size,
-        )
+        self.stream_handlers.resources = self.resources.clone();
+        let session_backend = self
+            .session_backend
+            .unwrap_or_else(|| Arc::new(MemoryBackend::new()));
+        let hooks = Hooks {
+            on_connect: self.on_connect,
+            on_auth: self.on_auth,
+            on_disconnect: self.on_disconnect,
+            resources: self.resources.build(),
+        };
+        let session_expiring_warning_in_seconds =
+            self.session_expiring_warning_in_seconds.unwrap_or(60);
+        Nitram {
+            state: Arc::new(NitramState::new(
+                session_backend,
+                hooks,
+                Duration::from_secs(session_expiring_warning_in_seconds),
+            )),
+            rpc_router_public: self.rpc_router_builder_public.build(),
+            rpc_router_private: self.rpc_router_builder_private.build(),
+            rpc_router_server_messages: self.rpc_router_builder_server_messages.build(),
+            registered_public_handlers: self.registered_public_handlers,
+            registered_private_handlers: self.registered_private_handlers,
+            registered_server_message_handlers: self.registered_server_messages_handlers,
+            stream_handlers: self.stream_handlers,
+            authenticator: self.authenticator,
+            tasks: TaskTracker::new(),
+            in_flight: TaskTracker::new(),
+            shutdown_token: CancellationToken::new(),
+            draining: Arc::new(AtomicBool::new(false)),
+            ping_interval_in_seconds: self.ping_interval_in_seconds.unwrap_or(30),
+            server_messages_interval_in_millis: self
+                .server_messages_interval_in_millis
+                .unwrap_or(1000),
+            server_messages_polling: self.server_messages_polling.unwrap_or(true),
+            session_resume_grace_period_in_seconds: self
+                .session_resume_grace_period_in_seconds
+                .unwrap_or(60),
+            session_expiring_warning_in_seconds,
+            max_concurrent_requests: self.max_concurrent_requests.unwrap_or(16).max(1),
+            max_pending_requests: self.max_pending_requests.unwrap_or(64).max(1),
+            default_protocol_version: self.default_protocol_version.unwrap_or_default(),
+            min_protocol_version: self.min_protocol_version.unwrap_or_default(),
+            batch_execution: self.batch_execution.unwrap_or_default(),
+            max_batch_size: self.max_batch_size.unwrap_or(32),
+            request_timeout_in_millis: self.request_timeout_in_millis.unwrap_or(0),
+            method_timeouts_in_millis: self.method_timeouts_in_millis,
+            timeout_in_seconds: self.timeout_in_seconds.unwrap_or(90),
+            // TODO: is there a better frame size?
+            // increase the maximum allowed frame size to 128KiB and aggregate continuation frames
+            max_frame_size: self.max_frame_size.unwrap_or(128 * 1024),
+        }
     }
 }
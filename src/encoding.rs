@@ -0,0 +1,66 @@
+use serde_json::Value;
+
+/// How the frames of a websocket are encoded, negotiated when it connects
+/// with the `nitram.json`, `nitram.msgpack` or `nitram.cbor` subprotocol, or
+/// the `nitram_encoding` query parameter.
+///
+/// With a binary encoding, server messages are sent as binary frames and
+/// binary requests get binary responses. Text frames are always JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// The subprotocols offered in the websocket handshake.
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["nitram.json", "nitram.msgpack", "nitram.cbor"];
+
+    /// From the `nitram_encoding` query parameter.
+    pub fn parse(encoding: &str) -> Option<Self> {
+        match encoding {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        Self::parse(subprotocol.strip_prefix("nitram.")?)
+    }
+
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).unwrap_or_default(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).unwrap_or_default(),
+            Encoding::Cbor => {
+                let mut bytes = vec![];
+                let _ = ciborium::into_writer(value, &mut bytes);
+                bytes
+            }
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<Value, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use derive_more::From;
 use rpc_router::RpcHandlerError;
 use serde::Serialize;
+use serde_json::Value;
 
 use crate::nice::{Nice, NiceMessage};
 
@@ -11,6 +12,14 @@ pub enum Error {
     MethodNotFound,
     NotAuthenticated,
     NotAuthorized,
+    SessionExpired,
+    /// The request was cancelled with `nitram_cancel`, or its websocket
+    /// disconnected
+    Cancelled,
+    /// The request took longer than its timeout or deadline
+    Timeout,
+    /// A request of the ws session with the same id is still in flight
+    DuplicateRequestId,
     RpcRequestError(String),
     TokenError(String),
 
@@ -39,6 +48,72 @@ pub enum MethodError {
     NotAuthorized,
     NotAuthenticated,
     NoResponse,
+    /// An application error, see `AppError`
+    Custom(CustomError),
+}
+
+/// Errors of the application that handlers can return with `?` or `.into()`
+/// besides the `MethodError` variants.
+///
+/// Serialize them adjacently tagged, so they respond with their own `code`
+/// and `data`, and derive `TS` to match on them in the client. The message
+/// is their `Display`.
+///
+/// ```ignore
+/// #[derive(Debug, Serialize, TS)]
+/// #[serde(tag = "code", content = "data", rename_all = "snake_case")]
+/// #[ts(export)]
+/// pub enum ChatError {
+///     ChannelFull,
+///     NameTaken { name: String },
+/// }
+/// impl AppError for ChatError {}
+/// ```
+pub trait AppError: Serialize + core::fmt::Display {}
+
+#[derive(Debug)]
+pub struct CustomError {
+    pub code: String,
+    pub message: String,
+    pub data: Value,
+}
+
+impl<E: AppError> From<E> for MethodError {
+    fn from(error: E) -> Self {
+        let (code, data) = match serde_json::to_value(&error) {
+            // Unit variant of an enum that is not tagged
+            Ok(Value::String(code)) => (code, Value::Null),
+            Ok(Value::Object(object)) if object.get("code").is_some_and(Value::is_string) => (
+                object["code"].as_str().unwrap_or_default().to_string(),
+                object.get("data").cloned().unwrap_or_default(),
+            ),
+            Ok(data) => ("app_error".to_string(), data),
+            Err(e) => {
+                tracing::error!("Could not serialize application error: {}", e);
+                ("app_error".to_string(), Value::Null)
+            }
+        };
+        MethodError::Custom(CustomError {
+            code,
+            message: error.to_string(),
+            data,
+        })
+    }
+}
+
+impl From<&MethodError> for Nice {
+    fn from(error: &MethodError) -> Self {
+        Nice::from(match error {
+            MethodError::NotFound => NiceMessage::NotFound,
+            MethodError::Server => NiceMessage::ServerError,
+            MethodError::NotAuthorized => NiceMessage::NotAuthorized,
+            MethodError::NotAuthenticated => NiceMessage::NotAuthenticated,
+            MethodError::NoResponse => NiceMessage::NoResponse,
+            MethodError::Custom(custom) => {
+                return Nice::custom(&custom.code, &custom.message, custom.data.clone());
+            }
+        })
+    }
 }
 
 impl Serialize for MethodError {
@@ -46,22 +121,6 @@ impl Serialize for MethodError {
     where
         S: serde::Serializer,
     {
-        match self {
-            MethodError::NotFound => {
-                serializer.serialize_str(&Nice::from(NiceMessage::NotFound).to_string())
-            }
-            MethodError::Server => {
-                serializer.serialize_str(&Nice::from(NiceMessage::ServerError).to_string())
-            }
-            MethodError::NotAuthorized => {
-                serializer.serialize_str(&Nice::from(NiceMessage::NotAuthorized).to_string())
-            }
-            MethodError::NotAuthenticated => {
-                serializer.serialize_str(&Nice::from(NiceMessage::NotAuthenticated).to_string())
-            }
-            MethodError::NoResponse => {
-                serializer.serialize_str(&Nice::from(NiceMessage::NoResponse).to_string())
-            }
-        }
+        serializer.serialize_str(&Nice::from(self).to_string())
     }
 }
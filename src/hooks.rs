@@ -0,0 +1,63 @@
+use rpc_router::Resources;
+use std::{future::Future, pin::Pin, sync::Arc};
+use uuid::Uuid;
+
+/// What a lifecycle hook gets to work with.
+#[derive(Clone)]
+pub struct HookContext {
+    pub ws_session_id: Uuid,
+    /// Set when the ws session is authenticated
+    pub user_id: Option<String>,
+    /// The resources registered with `NitramBuilder::add_resource`, e.g.
+    /// `ctx.resources.get::<ModelManager>()`
+    pub resources: Resources,
+}
+
+type HookFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type Hook = Arc<dyn Fn(HookContext) -> HookFuture + Send + Sync>;
+
+pub(crate) fn hook<F, Fut>(f: F) -> Hook
+where
+    F: Fn(HookContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |ctx| Box::pin(f(ctx)))
+}
+
+/// Lifecycle hooks registered with `NitramBuilder::on_connect`, `on_auth`
+/// and `on_disconnect`. They run in the order they were registered.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub(crate) on_connect: Vec<Hook>,
+    pub(crate) on_auth: Vec<Hook>,
+    pub(crate) on_disconnect: Vec<Hook>,
+    pub(crate) resources: Resources,
+}
+
+impl Hooks {
+    fn context(&self, ws_session_id: Uuid, user_id: Option<String>) -> HookContext {
+        HookContext {
+            ws_session_id,
+            user_id,
+            resources: self.resources.clone(),
+        }
+    }
+
+    async fn run(&self, hooks: &[Hook], ws_session_id: Uuid, user_id: Option<String>) {
+        for hook in hooks {
+            hook(self.context(ws_session_id, user_id.clone())).await;
+        }
+    }
+
+    pub(crate) async fn connect(&self, ws_session_id: Uuid, user_id: Option<String>) {
+        self.run(&self.on_connect, ws_session_id, user_id).await;
+    }
+
+    pub(crate) async fn auth(&self, ws_session_id: Uuid, user_id: String) {
+        self.run(&self.on_auth, ws_session_id, Some(user_id)).await;
+    }
+
+    pub(crate) async fn disconnect(&self, ws_session_id: Uuid, user_id: Option<String>) {
+        self.run(&self.on_disconnect, ws_session_id, user_id).await;
+    }
+}
@@ -0,0 +1,133 @@
+//! JSON-RPC 2.0 wire format, spoken by the websockets connected with
+//! `?nitram_protocol=jsonrpc`. Requests and responses follow the
+//! [specification](https://www.jsonrpc.org/specification), and server
+//! messages are sent as notifications whose method is the topic.
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::nice::Nice;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The request was cancelled with `nitram_cancel`, as in the Language Server
+/// Protocol.
+pub const REQUEST_CANCELLED: i64 = -32800;
+/// Every other error, e.g. `not_authorized` or an application error. The
+/// nitram `ErrorPayload` is in `data`.
+pub const SERVER_ERROR: i64 = -32000;
+/// The request took longer than its timeout.
+pub const REQUEST_TIMEOUT: i64 = -32001;
+
+pub(crate) struct JsonRpcRequest {
+    /// `None` for notifications, which get no response
+    pub id: Option<Value>,
+    pub method: String,
+    pub params: Value,
+}
+
+impl JsonRpcRequest {
+    pub fn parse(request: Value) -> Result<Self, JsonRpcResponse> {
+        // Echo the id when it is valid, even if the request is not
+        let id = match request.get("id") {
+            Some(id @ (Value::String(_) | Value::Number(_) | Value::Null)) => Some(id.clone()),
+            _ => None,
+        };
+        let invalid = || {
+            JsonRpcResponse::error(
+                id.clone().unwrap_or_default(),
+                JsonRpcError::new(INVALID_REQUEST, "invalid request"),
+            )
+        };
+        if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+            return Err(invalid());
+        }
+        if request.get("id").is_some() && id.is_none() {
+            return Err(invalid());
+        }
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return Err(invalid());
+        };
+        let params = match request.get("params") {
+            None => Value::Null,
+            Some(params @ (Value::Object(_) | Value::Array(_))) => params.clone(),
+            Some(_) => return Err(invalid()),
+        };
+        Ok(Self {
+            id,
+            method: method.to_string(),
+            params,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct JsonRpcResponse {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Result(Value),
+    Error(JsonRpcError),
+}
+
+impl JsonRpcResponse {
+    pub fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            outcome: Outcome::Result(result),
+            id,
+        }
+    }
+
+    pub fn error(id: Value, error: JsonRpcError) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            outcome: Outcome::Error(error),
+            id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct JsonRpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn from_nice(code: i64, nice: Nice) -> Self {
+        let payload = nice.payload();
+        Self {
+            code,
+            message: payload.message.clone(),
+            data: serde_json::to_value(payload).ok(),
+        }
+    }
+}
+
+/// A notification, for server messages.
+pub(crate) fn notification(method: &str, params: &Value) -> Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+    })
+}
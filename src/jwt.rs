@@ -0,0 +1,179 @@
+//! JWT authentication, behind the `jwt` feature. `NitramBuilder::set_jwt`
+//! registers the `Authenticate` and `Refresh` handlers and authenticates the
+//! websockets during the handshake with the same token.
+//!
+//! ```ignore
+//! let jwt = JwtAuth::from_secret(b"secret")
+//!     .set_issuer("https://auth.example.com")
+//!     .set_audience("chat")
+//!     .set_leeway(30);
+//! let nitram = NitramBuilder::default().set_jwt(jwt).build();
+//! ```
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use chrono::DateTime;
+use jsonwebtoken::{decode, TokenData, Validation};
+use rpc_router::RpcResource;
+use serde_json::{Map, Value};
+
+pub use jsonwebtoken::{Algorithm, DecodingKey};
+
+use crate::auth::{
+    AuthenticateParams, AuthenticatedUser, Authenticator, RefreshParams, TokenSource,
+    WSSessionAnonymResource,
+};
+use crate::error::{MethodError, MethodResult};
+
+/// How tokens are verified and mapped to a user.
+#[derive(Clone, RpcResource)]
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+    user_id_claim: &'static str,
+    expires_at_claim: &'static str,
+    token_sources: Vec<TokenSource>,
+}
+
+impl JwtAuth {
+    /// Verifies tokens signed with `algorithm`, e.g. `Algorithm::HS512` or
+    /// `Algorithm::PS256`.
+    pub fn new(key: DecodingKey, algorithm: Algorithm) -> Self {
+        let mut validation = Validation::new(algorithm);
+        // The mapped claims are required instead, see `decode`
+        validation.set_required_spec_claims::<&str>(&[]);
+        JwtAuth {
+            key,
+            validation,
+            user_id_claim: "sub",
+            expires_at_claim: "exp",
+            token_sources: TokenSource::DEFAULTS.to_vec(),
+        }
+    }
+
+    /// HS256 with a shared secret.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+
+    /// RS256 with the PEM encoded public key of the issuer.
+    pub fn from_rsa_pem(pem: &[u8]) -> Result<Self, String> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(|e| e.to_string())?;
+        Ok(Self::new(key, Algorithm::RS256))
+    }
+
+    /// Only accepts tokens with this `iss` claim.
+    pub fn set_issuer(mut self, issuer: &str) -> Self {
+        self.validation.set_issuer(&[issuer]);
+        self.validation
+            .required_spec_claims
+            .insert("iss".to_string());
+        self
+    }
+
+    /// Only accepts tokens with this `aud` claim. Without it, tokens that
+    /// have an `aud` claim are rejected.
+    pub fn set_audience(mut self, audience: &str) -> Self {
+        self.validation.set_audience(&[audience]);
+        self.validation
+            .required_spec_claims
+            .insert("aud".to_string());
+        self
+    }
+
+    /// Seconds of clock skew allowed when checking the expiry (default = 60).
+    pub fn set_leeway(mut self, leeway_in_seconds: u64) -> Self {
+        self.validation.leeway = leeway_in_seconds;
+        self
+    }
+
+    /// Claim with the user id (default = `sub`).
+    pub fn set_user_id_claim(mut self, claim: &'static str) -> Self {
+        self.user_id_claim = claim;
+        self
+    }
+
+    /// Claim with the expiry as a unix timestamp, which becomes
+    /// `UserSession::expires_at` (default = `exp`).
+    pub fn set_expires_at_claim(mut self, claim: &'static str) -> Self {
+        self.expires_at_claim = claim;
+        self
+    }
+
+    /// Where the token is read from during the handshake (default =
+    /// `TokenSource::DEFAULTS`).
+    pub fn set_token_sources(mut self, sources: &[TokenSource]) -> Self {
+        self.token_sources = sources.to_vec();
+        self
+    }
+
+    /// Verifies the token and maps its claims to a user. The error says why
+    /// the token was rejected.
+    pub fn decode(&self, token: &str) -> Result<AuthenticatedUser, String> {
+        let TokenData { claims, .. } =
+            decode::<Map<String, Value>>(token, &self.key, &self.validation)
+                .map_err(|e| e.to_string())?;
+        let user_id = match claims.get(self.user_id_claim) {
+            Some(Value::String(user_id)) => user_id.clone(),
+            Some(Value::Number(user_id)) => user_id.to_string(),
+            _ => return Err(format!("missing {}", self.user_id_claim)),
+        };
+        let expires_at = claims
+            .get(self.expires_at_claim)
+            .and_then(Value::as_i64)
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .ok_or_else(|| format!("missing {}", self.expires_at_claim))?;
+        // `exp` is also checked by `jsonwebtoken`, a mapped claim is not
+        let leeway = chrono::Duration::seconds(self.validation.leeway as i64);
+        if expires_at + leeway <= chrono::Utc::now() {
+            return Err("expired".to_string());
+        }
+        Ok(AuthenticatedUser {
+            user_id,
+            expires_at,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Authenticator for JwtAuth {
+    async fn authenticate(&self, req: &HttpRequest) -> Option<AuthenticatedUser> {
+        let token = TokenSource::find(&self.token_sources, req)?;
+        self.decode(&token)
+            .inspect_err(|e| tracing::debug!("Handshake token rejected: {}", e))
+            .ok()
+    }
+}
+
+/// The `Authenticate` handler registered by `NitramBuilder::set_jwt`.
+/// Responds with the user id.
+pub async fn authenticate_handler(
+    jwt: JwtAuth,
+    anonym_session: WSSessionAnonymResource,
+    params: AuthenticateParams,
+) -> MethodResult<String> {
+    let user = jwt.decode(&params.token).map_err(|e| {
+        tracing::debug!("Token rejected: {}", e);
+        MethodError::NotAuthenticated
+    })?;
+    anonym_session.auth(&user.user_id, user.expires_at).await;
+    Ok(user.user_id)
+}
+
+/// The `Refresh` handler registered by `NitramBuilder::set_jwt`. Extends the
+/// session with the expiry of a new token of the same user, keeping its store
+/// and topic registrations. Responds with the user id.
+pub async fn refresh_handler(
+    jwt: JwtAuth,
+    session: WSSessionAnonymResource,
+    params: RefreshParams,
+) -> MethodResult<String> {
+    let user = jwt.decode(&params.token).map_err(|e| {
+        tracing::debug!("Token rejected: {}", e);
+        MethodError::NotAuthenticated
+    })?;
+    if !session.refresh(&user.user_id, user.expires_at).await {
+        return Err(MethodError::NotAuthenticated);
+    }
+    Ok(user.user_id)
+}
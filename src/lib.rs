@@ -11,15 +11,24 @@ mod messages;
 mod nitram;
 
 pub mod auth;
+pub mod backend;
+pub mod encoding;
 pub mod error;
+pub mod hooks;
+pub mod json_rpc;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 pub mod models;
 pub mod nice;
+pub mod params;
+pub mod stream;
 pub mod ws;
 pub use nitram::*;
 
 pub use builder::NitramBuilder;
+pub use messages::{BatchExecution, NitramCapabilities, NitramHello, ProtocolVersion};
 
-pub use auth::AuthenticateParams;
+pub use auth::{AuthenticateParams, RefreshParams};
 
 #[derive(TS)]
 #[ts(export, export_to = "Nitram.ts")]
@@ -50,7 +59,10 @@ macro_rules! nitram_handler {
         $name:ident,
         $params_ty:ident,
         $output_ty:ty,
-        $( $param_name:ident : $param_ty:ty ),*
+        $(
+            $param_name:ident : $param_ty:ty
+            $( => [ $( $rule:ident ( $( $rule_arg:expr ),* ) ),* $(,)? ] )?
+        ),*
     ) => {
         #[derive(Deserialize, Clone, TS)]
         #[ts(export, export_to = "API/Params.ts")]
@@ -60,7 +72,31 @@ macro_rules! nitram_handler {
             )*
         }
 
-        impl IntoParams for $params_ty {}
+        impl IntoParams for $params_ty {
+            fn into_params(
+                value: Option<$crate::params::Value>,
+            ) -> $crate::params::Result<Self> {
+                $crate::params::parse(value)
+            }
+        }
+
+        impl $crate::params::Validate for $params_ty {
+            fn validate(&self) -> Vec<$crate::params::ParamError> {
+                #[allow(unused_mut)]
+                let mut errors = Vec::new();
+                $( $( $(
+                    if let Err(message) =
+                        $crate::params::rules::$rule(&self.$param_name, $( $rule_arg ),*)
+                    {
+                        errors.push($crate::params::ParamError::new(
+                            concat!("params.", stringify!($param_name)),
+                            message,
+                        ));
+                    }
+                )* )? )*
+                errors
+            }
+        }
 
         #[derive(TS)]
         #[ts(export, export_to = "API/index.ts")]
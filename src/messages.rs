@@ -1,6 +1,13 @@
+use chrono::{DateTime, Utc};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::sync::OnceLock;
 use ts_rs::TS;
+use uuid::Uuid;
+
+use crate::json_rpc;
+use crate::nice::{Nice, NiceMessage};
 
 #[derive(Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -8,6 +15,104 @@ pub struct NitramRequest {
     pub id: String,
     pub method: String,
     pub params: Value,
+    /// When the client stops waiting for the response. The request times out
+    /// then if it is still in flight, see `NitramBuilder::set_request_timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub deadline: Option<DateTime<Utc>>,
+}
+
+/// A frame that is not a valid `NitramRequest`. The id and method are kept
+/// when they could be read, so the client can match the error with its
+/// request.
+#[derive(Debug)]
+pub struct InvalidRequest {
+    pub id: Option<String>,
+    pub method: Option<String>,
+    pub error: Box<Nice>,
+}
+
+impl InvalidRequest {
+    /// Rejects a request, valid or not, keeping its id and method.
+    pub fn reject(request: Result<NitramRequest, InvalidRequest>, error: impl Into<Nice>) -> Self {
+        let error = Box::new(error.into());
+        match request {
+            Ok(request) => Self {
+                id: Some(request.id),
+                method: Some(request.method),
+                error,
+            },
+            Err(invalid) => Self { error, ..invalid },
+        }
+    }
+}
+
+impl NitramRequest {
+    pub fn parse(text: &str) -> Result<Self, InvalidRequest> {
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return Err(InvalidRequest {
+                id: find_string_field(text, "id"),
+                method: find_string_field(text, "method"),
+                error: Box::new(NiceMessage::InvalidJson.into()),
+            });
+        };
+        Self::from_value(value)
+    }
+
+    /// A request of a batch.
+    pub fn from_value(value: Value) -> Result<Self, InvalidRequest> {
+        let id = match value.get("id") {
+            Some(Value::String(id)) => Some(id.clone()),
+            Some(Value::Number(id)) => Some(id.to_string()),
+            _ => None,
+        };
+        let method = value
+            .get("method")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let invalid = |error: NiceMessage| InvalidRequest {
+            id: id.clone(),
+            method: method.clone(),
+            error: Box::new(error.into()),
+        };
+        if id.is_none() {
+            return Err(invalid(NiceMessage::MissingId));
+        }
+        if method.is_none() {
+            return Err(invalid(NiceMessage::MissingMethod));
+        }
+        let params = match value.get("params") {
+            None => Value::Null,
+            Some(params @ (Value::Null | Value::Object(_))) => params.clone(),
+            Some(_) => return Err(invalid(NiceMessage::InvalidParams)),
+        };
+        let deadline = match value.get("deadline") {
+            None | Some(Value::Null) => None,
+            Some(deadline) => match serde_json::from_value(deadline.clone()) {
+                Ok(deadline) => Some(deadline),
+                Err(_) => return Err(invalid(NiceMessage::InvalidDeadline)),
+            },
+        };
+        Ok(NitramRequest {
+            id: id.unwrap_or_default(),
+            method: method.unwrap_or_default(),
+            params,
+            deadline,
+        })
+    }
+}
+
+/// Best-effort lookup of a string field in a frame that is not valid JSON,
+/// e.g. a truncated one.
+fn find_string_field(text: &str, field: &str) -> Option<String> {
+    static ID: OnceLock<Regex> = OnceLock::new();
+    static METHOD: OnceLock<Regex> = OnceLock::new();
+    let regex = match field {
+        "id" => ID.get_or_init(|| Regex::new(r#""id"\s*:\s*"([^"\\]*)""#).unwrap()),
+        "method" => METHOD.get_or_init(|| Regex::new(r#""method"\s*:\s*"([^"\\]*)""#).unwrap()),
+        _ => return None,
+    };
+    regex.captures(text).map(|captures| captures[1].to_string())
 }
 
 #[derive(Serialize, TS)]
@@ -19,20 +124,188 @@ pub struct NitramResponse {
     pub ok: bool,
 }
 
-impl NitramResponse {
-    pub fn error(s: impl Into<String>) -> Self {
-        Self {
-            id: "_err".to_string(),
-            method: "_err".to_string(),
-            response: serde_json::Value::String(s.into()),
-            ok: false,
+/// An item of a stream handler, sent before the `NitramResponse` of the
+/// request.
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct NitramPartialResponse {
+    pub id: String,
+    pub method: String,
+    pub partial: Value,
+}
+
+/// Wire format spoken with a client, chosen per websocket with the
+/// `nitram_protocol` query parameter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// Errors are `(~ message ~)` strings
+    #[default]
+    V1,
+    /// Errors are `ErrorPayload` objects
+    V2,
+    /// Standard JSON-RPC 2.0, see `json_rpc`
+    JsonRpc,
+}
+
+impl ProtocolVersion {
+    pub const ALL: [ProtocolVersion; 3] = [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::JsonRpc,
+    ];
+
+    /// `None` for JSON-RPC, which is not versioned with the nitram envelope.
+    pub fn number(&self) -> Option<u32> {
+        match self {
+            ProtocolVersion::V1 => Some(1),
+            ProtocolVersion::V2 => Some(2),
+            ProtocolVersion::JsonRpc => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "1",
+            ProtocolVersion::V2 => "2",
+            ProtocolVersion::JsonRpc => "jsonrpc",
+        }
+    }
+
+    pub fn parse(version: &str) -> Option<Self> {
+        match version {
+            "1" => Some(ProtocolVersion::V1),
+            "2" => Some(ProtocolVersion::V2),
+            "jsonrpc" => Some(ProtocolVersion::JsonRpc),
+            _ => None,
         }
     }
 }
 
-#[derive(Serialize, TS)]
+/// The first frame sent on a websocket, as the payload of a `nitram_hello`
+/// server message. The client can check it is compatible and adapt its
+/// keepalive and queueing.
+#[derive(Clone, Debug, Serialize, TS)]
+#[ts(export, export_to = "NitramHello.ts")]
+pub struct NitramHello {
+    pub ws_session_id: Uuid,
+    /// Set when the websocket is authenticated, during the handshake or by
+    /// resuming a session
+    pub user_id: Option<String>,
+    /// e.g. `2`, see `ProtocolVersion`
+    pub protocol_version: String,
+    /// e.g. `json`, see `Encoding`
+    pub encoding: String,
+    pub server_time: DateTime<Utc>,
+    #[ts(type = "number")]
+    pub ping_interval_in_seconds: u64,
+    /// The websocket is closed when no pong is received for this long
+    #[ts(type = "number")]
+    pub timeout_in_seconds: u64,
+    pub max_frame_size: usize,
+    pub max_concurrent_requests: usize,
+    pub max_batch_size: usize,
+    /// Protocol versions the server speaks, e.g. `["2", "jsonrpc"]`
+    pub supported_protocol_versions: Vec<String>,
+    /// Available without authentication
+    pub public: NitramCapabilities,
+    /// Available once authenticated
+    pub private: NitramCapabilities,
+}
+
+#[derive(Clone, Debug, Default, Serialize, TS)]
+#[ts(export, export_to = "NitramHello.ts")]
+pub struct NitramCapabilities {
+    pub methods: Vec<String>,
+    /// Topics of server messages
+    pub topics: Vec<String>,
+}
+
+/// How the requests of a batch (an array of requests in one frame) are
+/// handled. The responses are sent together, in the order of the requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BatchExecution {
+    /// Concurrently, the batch takes as long as its slowest request
+    #[default]
+    Parallel,
+    /// One after the other, e.g. when requests depend on the previous ones
+    Sequential,
+}
+
+#[derive(Clone, Serialize, TS)]
 #[ts(export)]
 pub struct NitramServerMessage {
     pub topic: String,
     pub payload: Value,
 }
+
+impl NitramServerMessage {
+    /// The frame for the protocol version. JSON-RPC clients get a
+    /// notification whose method is the topic.
+    pub fn to_json(&self, protocol: ProtocolVersion) -> Value {
+        match protocol {
+            ProtocolVersion::JsonRpc => json_rpc::notification(&self.topic, &self.payload),
+            _ => serde_json::to_value(self).unwrap_or_default(),
+        }
+    }
+
+    pub fn list_to_json(server_messages: &[Self], protocol: ProtocolVersion) -> Value {
+        server_messages
+            .iter()
+            .map(|server_message| server_message.to_json(protocol))
+            .collect()
+    }
+
+    /// Sent when the user session expired and the ws session was downgraded
+    /// to anonymous.
+    pub fn session_expired() -> Self {
+        Self {
+            topic: "nitram_session_expired".to_string(),
+            payload: Value::Null,
+        }
+    }
+
+    /// Sent once when the user session expires soon, so the client can
+    /// refresh it before it is downgraded to anonymous.
+    pub fn session_expiring(expires_at: DateTime<Utc>) -> Self {
+        Self {
+            topic: "nitram_session_expiring".to_string(),
+            payload: serde_json::json!({ "expires_at": expires_at }),
+        }
+    }
+
+    /// Sent first when the websocket connects.
+    pub fn hello(hello: NitramHello) -> Self {
+        Self {
+            topic: "nitram_hello".to_string(),
+            payload: serde_json::to_value(hello).unwrap_or_default(),
+        }
+    }
+
+    /// Sent after the hello when the client asked for a protocol version the
+    /// server doesn't speak. Every request of the websocket then fails with
+    /// `upgrade_required`.
+    pub fn upgrade_required(payload: Value) -> Self {
+        Self {
+            topic: "nitram_upgrade_required".to_string(),
+            payload,
+        }
+    }
+
+    /// Sent when the server is shutting down. The websocket is closed once
+    /// the requests in flight are answered, and the client should reconnect.
+    pub fn shutdown() -> Self {
+        Self {
+            topic: "nitram_shutdown".to_string(),
+            payload: serde_json::json!({ "reconnect": true }),
+        }
+    }
+
+    /// Sent when the websocket connects, with the token to resume the session
+    /// after a reconnect.
+    pub fn resume_token(token: String, resumed: bool) -> Self {
+        Self {
+            topic: "nitram_resume_token".to_string(),
+            payload: serde_json::json!({ "token": token, "resumed": resumed }),
+        }
+    }
+}
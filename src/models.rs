@@ -19,16 +19,14 @@ pub struct UserSession {
     pub expires_at: DateTime<Utc>,
 }
 
-#[derive(Clone, RpcResource)]
+#[derive(Clone, Default, RpcResource)]
 pub struct Store {
     pub kv: Arc<Mutex<HashMap<String, Value>>>,
 }
 
 impl Store {
     pub fn new() -> Self {
-        Self {
-            kv: Arc::new(Mutex::new(HashMap::new())),
-        }
+        Self::default()
     }
     pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
         let store = self.kv.lock().await;
@@ -43,6 +41,14 @@ impl Store {
     }
 }
 
+impl From<HashMap<String, Value>> for Store {
+    fn from(kv: HashMap<String, Value>) -> Self {
+        Self {
+            kv: Arc::new(Mutex::new(kv)),
+        }
+    }
+}
+
 pub struct UserPayload {
     pub user_session: UserSession,
     pub store: Store,
@@ -1,12 +1,31 @@
+use serde::Serialize;
 use serde_json::Value;
+use ts_rs::TS;
 
+use crate::messages::ProtocolVersion;
+
+/// Serialized, it is the stable `code` of an `ErrorPayload`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+#[ts(export, rename = "ErrorCode")]
 pub enum NiceMessage {
     ServerError,
     NotFound,
     NotAuthorized,
     NotAuthenticated,
+    SessionExpired,
     BadRequest,
     NoResponse,
+    InvalidJson,
+    MissingId,
+    MissingMethod,
+    InvalidParams,
+    BatchTooLarge,
+    UpgradeRequired,
+    Cancelled,
+    Timeout,
+    InvalidDeadline,
+    DuplicateId,
 }
 
 impl core::fmt::Display for NiceMessage {
@@ -19,49 +38,119 @@ impl core::fmt::Display for NiceMessage {
                 NiceMessage::NotFound => "not found".to_string(),
                 NiceMessage::NotAuthorized => "not authorized".to_string(),
                 NiceMessage::NotAuthenticated => "not authenticated".to_string(),
+                NiceMessage::SessionExpired => "session expired".to_string(),
                 NiceMessage::BadRequest => "bad request".to_string(),
                 NiceMessage::NoResponse => "no response".to_string(),
+                NiceMessage::InvalidJson => "invalid json".to_string(),
+                NiceMessage::MissingId => "missing id".to_string(),
+                NiceMessage::MissingMethod => "missing method".to_string(),
+                NiceMessage::InvalidParams => "params must be an object".to_string(),
+                NiceMessage::BatchTooLarge => "batch too large".to_string(),
+                NiceMessage::UpgradeRequired => "upgrade required".to_string(),
+                NiceMessage::Cancelled => "cancelled".to_string(),
+                NiceMessage::Timeout => "timeout".to_string(),
+                NiceMessage::InvalidDeadline => "invalid deadline".to_string(),
+                NiceMessage::DuplicateId => "duplicate id".to_string(),
             }
         )
     }
 }
 
+impl NiceMessage {
+    /// Stable code, the serialized `NiceMessage`.
+    pub fn code(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(Value::String(code)) => code,
+            _ => String::new(),
+        }
+    }
+}
+
+/// Error response of protocol version 2. `code` is an `ErrorCode`, or the
+/// code of an application error (see `error::AppError`).
+#[derive(Debug, Serialize, TS)]
+#[ts(export)]
+pub struct ErrorPayload {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub data: Option<Value>,
+}
+
+#[derive(Debug)]
 pub struct Nice {
-    msg: NiceMessage,
+    code: String,
+    message: String,
     data: Value,
 }
 
 impl From<NiceMessage> for Nice {
     fn from(msg: NiceMessage) -> Self {
-        Self {
-            msg,
-            data: Value::Null,
-        }
+        Self::with_data(msg, Value::Null)
     }
 }
 
 impl Nice {
     pub fn with_data(msg: NiceMessage, data: Value) -> Self {
-        Self { msg, data }
+        Self::custom(msg.code(), msg.to_string(), data)
+    }
+
+    /// Error with a code that is not a `NiceMessage`, e.g. an application
+    /// error.
+    pub fn custom(code: impl Into<String>, message: impl Into<String>, data: Value) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+            data,
+        }
+    }
+
+    pub fn payload(&self) -> ErrorPayload {
+        ErrorPayload {
+            code: self.code.clone(),
+            message: self.message.clone(),
+            data: match self.data {
+                Value::Null => None,
+                _ => Some(self.data.clone()),
+            },
+        }
+    }
+
+    /// The error response for the protocol version: a `(~ message ~)` string
+    /// for version 1, an `ErrorPayload` object from version 2.
+    pub fn into_value(self, protocol: ProtocolVersion) -> Value {
+        match protocol {
+            // Legacy clients compare bad requests to `(~ bad request ~)`, the
+            // param errors are only sent from version 2
+            ProtocolVersion::V1 if self.code == NiceMessage::BadRequest.code() => {
+                Nice::from(NiceMessage::BadRequest).into()
+            }
+            ProtocolVersion::V1 => self.into(),
+            ProtocolVersion::V2 | ProtocolVersion::JsonRpc => {
+                serde_json::to_value(self.payload()).unwrap_or_default()
+            }
+        }
     }
 }
 
-impl ToString for Nice {
-    fn to_string(&self) -> String {
+impl core::fmt::Display for Nice {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::result::Result<(), core::fmt::Error> {
         match self.data {
-            Value::Null => format!("(~ {} ~)", self.msg),
-            _ => format!(
+            Value::Null => write!(fmt, "(~ {} ~)", self.message),
+            _ => write!(
+                fmt,
                 "(~ {} ~~ {} ~)",
-                self.msg,
+                self.message,
                 serde_json::to_string(&self.data).unwrap_or_default()
             ),
         }
     }
 }
 
-impl Into<serde_json::Value> for Nice {
-    fn into(self) -> serde_json::Value {
-        self.to_string().into()
+impl From<Nice> for serde_json::Value {
+    fn from(nice: Nice) -> Self {
+        nice.to_string().into()
     }
 }
 
@@ -86,4 +175,24 @@ mod tests {
             "(~ server error ~~ {\"key\":\"value\"} ~)"
         );
     }
+
+    #[test]
+    fn test_code() {
+        assert_eq!(NiceMessage::ServerError.code(), "server_error");
+        assert_eq!(NiceMessage::BatchTooLarge.code(), "batch_too_large");
+    }
+
+    #[test]
+    fn test_payload() {
+        let nice = Nice::from(NiceMessage::NotAuthorized);
+        assert_eq!(
+            nice.into_value(ProtocolVersion::V2),
+            serde_json::json!({ "code": "not_authorized", "message": "not authorized" })
+        );
+        let nice = Nice::with_data(NiceMessage::BadRequest, serde_json::json!("oops"));
+        assert_eq!(
+            nice.into_value(ProtocolVersion::V2),
+            serde_json::json!({ "code": "bad_request", "message": "bad request", "data": "oops" })
+        );
+    }
 }
@@ -1,57 +1,502 @@
+use actix_web::HttpRequest;
 use bytestring::ByteString;
-use rpc_router::{Request, Resources, Router};
+use chrono::{DateTime, Utc};
+use futures_util::{future::join_all, StreamExt};
+use rpc_router::{CallError, Request, Resources, Router, RpcResource};
 use serde_json::{json, Value};
-use std::{collections::BTreeMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use uuid::Uuid;
 
-use crate::auth::{NitramSession, WSSessionAnonymResource, WSSessionAuthedResource};
+use crate::auth::{Authenticator, NitramSession, WSSessionAnonymResource, WSSessionAuthedResource};
+use crate::backend::SessionBackend;
+use crate::encoding::Encoding;
 use crate::error::{Error, MethodError, Result};
-use crate::messages::{NitramRequest, NitramResponse, NitramServerMessage};
+use crate::hooks::Hooks;
+use crate::json_rpc::{self, JsonRpcError, JsonRpcRequest, JsonRpcResponse};
+use crate::messages::{
+    BatchExecution, InvalidRequest, NitramCapabilities, NitramHello, NitramPartialResponse,
+    NitramRequest, NitramResponse, NitramServerMessage, ProtocolVersion,
+};
 use crate::models::{UserPayload, UserSession};
 use crate::nice::{Nice, NiceMessage};
+use crate::params::{ParamError, ParamErrors};
+use crate::stream::{BoxedStreamHandler, StreamHandlers};
+use crate::NitramBuilder;
 
 pub struct NitramState {
-    ws_sessions: BTreeMap<Uuid, NitramSession>,
+    backend: Arc<dyn SessionBackend>,
+    hooks: Hooks,
+    /// Channels to push server messages to the connected websockets
+    outboxes: RwLock<Outboxes>,
+    /// Protocol versions of the connected websockets that asked for one
+    protocol_versions: RwLock<HashMap<Uuid, ProtocolVersion>>,
+    /// Encodings of the connected websockets that negotiated one
+    encodings: RwLock<HashMap<Uuid, Encoding>>,
+    /// Protocol versions asked for by the connected websockets that are not
+    /// supported
+    unsupported_protocol_versions: RwLock<HashMap<Uuid, String>>,
+    /// Requests in flight of the connected websockets, by request id
+    cancellations: RwLock<HashMap<Uuid, HashMap<String, CancellationToken>>>,
+    /// How long before `expires_at` the client is told its session is
+    /// expiring, zero to never tell it
+    session_expiring_warning: Duration,
+    /// The `expires_at` each ws session was told about, so it is told once
+    expiring_warnings: RwLock<HashMap<Uuid, DateTime<Utc>>>,
 }
 
+type Outboxes = HashMap<Uuid, mpsc::UnboundedSender<NitramServerMessage>>;
+
 impl NitramState {
-    fn new() -> Self {
+    pub(crate) fn new(
+        backend: Arc<dyn SessionBackend>,
+        hooks: Hooks,
+        session_expiring_warning: Duration,
+    ) -> Self {
         NitramState {
-            ws_sessions: BTreeMap::new(),
+            backend,
+            hooks,
+            outboxes: RwLock::new(HashMap::new()),
+            protocol_versions: RwLock::new(HashMap::new()),
+            encodings: RwLock::new(HashMap::new()),
+            unsupported_protocol_versions: RwLock::new(HashMap::new()),
+            cancellations: RwLock::new(HashMap::new()),
+            session_expiring_warning,
+            expiring_warnings: RwLock::new(HashMap::new()),
         }
     }
+
+    fn outboxes(&self) -> RwLockReadGuard<'_, Outboxes> {
+        self.outboxes.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn outboxes_mut(&self) -> RwLockWriteGuard<'_, Outboxes> {
+        self.outboxes
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn protocol_versions(&self) -> RwLockReadGuard<'_, HashMap<Uuid, ProtocolVersion>> {
+        self.protocol_versions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn protocol_versions_mut(&self) -> RwLockWriteGuard<'_, HashMap<Uuid, ProtocolVersion>> {
+        self.protocol_versions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn encodings(&self) -> RwLockReadGuard<'_, HashMap<Uuid, Encoding>> {
+        self.encodings
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn encodings_mut(&self) -> RwLockWriteGuard<'_, HashMap<Uuid, Encoding>> {
+        self.encodings
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn unsupported_protocol_versions(&self) -> RwLockReadGuard<'_, HashMap<Uuid, String>> {
+        self.unsupported_protocol_versions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn unsupported_protocol_versions_mut(&self) -> RwLockWriteGuard<'_, HashMap<Uuid, String>> {
+        self.unsupported_protocol_versions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn cancellations_mut(
+        &self,
+    ) -> RwLockWriteGuard<'_, HashMap<Uuid, HashMap<String, CancellationToken>>> {
+        self.cancellations
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn expiring_warnings_mut(&self) -> RwLockWriteGuard<'_, HashMap<Uuid, DateTime<Utc>>> {
+        self.expiring_warnings
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forgets everything about the websocket of the ws session, and cancels
+    /// its requests in flight.
+    fn disconnect(&self, ws_session_id: &Uuid) {
+        self.outboxes_mut().remove(ws_session_id);
+        self.protocol_versions_mut().remove(ws_session_id);
+        self.encodings_mut().remove(ws_session_id);
+        self.unsupported_protocol_versions_mut()
+            .remove(ws_session_id);
+        self.expiring_warnings_mut().remove(ws_session_id);
+        let cancellations = self.cancellations_mut().remove(ws_session_id);
+        for token in cancellations.into_iter().flat_map(HashMap::into_values) {
+            token.cancel();
+        }
+    }
+
+    /// Keeps track of a request in flight, so it can be cancelled. `None` if
+    /// a request with the same key is already in flight.
+    fn start_request(&self, ws_session_id: &Uuid, request_id: &str) -> Option<CancellationToken> {
+        let mut cancellations = self.cancellations_mut();
+        let requests = cancellations.entry(*ws_session_id).or_default();
+        if requests.contains_key(request_id) {
+            return None;
+        }
+        let token = CancellationToken::new();
+        requests.insert(request_id.to_string(), token.clone());
+        Some(token)
+    }
+
+    fn end_request(&self, ws_session_id: &Uuid, request_id: &str) {
+        let mut cancellations = self.cancellations_mut();
+        if let Some(requests) = cancellations.get_mut(ws_session_id) {
+            requests.remove(request_id);
+            if requests.is_empty() {
+                cancellations.remove(ws_session_id);
+            }
+        }
+    }
+
+    /// Cancels a request in flight of the ws session. Returns true if the
+    /// request was in flight.
+    fn cancel_request(&self, ws_session_id: &Uuid, request_id: &str) -> bool {
+        let cancellations = self.cancellations_mut();
+        let token = cancellations
+            .get(ws_session_id)
+            .and_then(|requests| requests.get(request_id));
+        if let Some(token) = token {
+            token.cancel();
+        }
+        token.is_some()
+    }
 }
 
 impl NitramState {
-    pub fn add_anonym_ws_session(&mut self) -> Uuid {
+    pub async fn add_anonym_ws_session(&self) -> Uuid {
         let id = Uuid::new_v4();
-        self.ws_sessions.insert(id, NitramSession::Anonymous);
+        self.backend.insert(id, NitramSession::Anonymous).await;
         id
     }
-    pub fn auth_ws_session(&mut self, ws_session_id: Uuid, user_session: UserSession) -> () {
-        self.ws_sessions
-            .insert(ws_session_id, NitramSession::new_auth(user_session));
-        tracing::debug!("auth_ws_session sessions: {:?}", self.ws_sessions);
+    pub async fn auth_ws_session(&self, ws_session_id: Uuid, user_session: UserSession) {
+        let user_id = user_session.user_id.clone();
+        self.backend.auth(ws_session_id, user_session).await;
+        tracing::debug!(sess = ws_session_id.to_string(), "auth_ws_session");
+        self.hooks.auth(ws_session_id, user_id).await;
+    }
+
+    pub async fn issue_resume_token(&self, ws_session_id: Uuid) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        self.backend
+            .set_resume_token(&ws_session_id, token.clone())
+            .await;
+        token
+    }
+
+    pub async fn remove_ws_session(&self, ws_session_id: &Uuid) -> Option<NitramSession> {
+        self.disconnect(ws_session_id);
+        self.backend.remove(ws_session_id).await
+    }
+
+    /// Keeps the ws session around so it can be resumed, but stops pushing
+    /// server messages to it.
+    pub async fn detach_ws_session(&self, ws_session_id: &Uuid) {
+        self.disconnect(ws_session_id);
+        self.backend.detach(ws_session_id, Utc::now()).await;
+    }
+
+    /// Removes the detached sessions that were not resumed within the grace
+    /// period.
+    pub async fn purge_detached_ws_sessions(&self, grace_period: Duration) {
+        let before = Utc::now() - grace_period;
+        for ws_session_id in self.backend.detached_before(before).await {
+            self.remove_ws_session(&ws_session_id).await;
+        }
+    }
+
+    /// Moves the session that was handed `token` into `ws_session_id`, which
+    /// is the session of the new websocket. Returns true if the session was
+    /// resumed.
+    pub async fn resume_ws_session(
+        &self,
+        token: &str,
+        ws_session_id: Uuid,
+        grace_period: Duration,
+    ) -> bool {
+        self.purge_detached_ws_sessions(grace_period).await;
+        let Some(previous_id) = self.backend.find_by_resume_token(token).await else {
+            return false;
+        };
+        if previous_id == ws_session_id {
+            return false;
+        }
+        let Some(mut session) = self.remove_ws_session(&previous_id).await else {
+            return false;
+        };
+        if let NitramSession::Authenticated { user_session, .. } = &mut session {
+            user_session.id = ws_session_id;
+        }
+        tracing::debug!(
+            sess = ws_session_id.to_string(),
+            previous = previous_id.to_string(),
+            "resume_ws_session: {:?}",
+            session
+        );
+        self.backend.insert(ws_session_id, session).await;
+        true
+    }
+
+    /// Extends the user session of the ws session, e.g. with the expiry of a
+    /// new token, keeping its store and topic registrations. Returns false if
+    /// the ws session is not authenticated as `user_id`, or already expired.
+    pub async fn refresh_ws_session(
+        &self,
+        ws_session_id: &Uuid,
+        user_id: &str,
+        expires_at: DateTime<Utc>,
+    ) -> bool {
+        let session = self.backend.get(ws_session_id).await;
+        if self.expire_if_needed(ws_session_id, session.as_ref()).await {
+            return false;
+        }
+        if session.and_then(|session| session.user_id()).as_deref() != Some(user_id) {
+            return false;
+        }
+        let refreshed = self.backend.refresh(ws_session_id, expires_at).await;
+        tracing::debug!(
+            sess = ws_session_id.to_string(),
+            refreshed = refreshed,
+            "refresh_ws_session"
+        );
+        refreshed
+    }
+
+    /// Resets the ws session to anonymous, dropping its store and topic
+    /// registrations. Returns true if the session was authenticated.
+    pub async fn deauth_ws_session(&self, ws_session_id: &Uuid) -> bool {
+        let Some(session) = self.backend.get(ws_session_id).await else {
+            return false;
+        };
+        let was_authenticated = matches!(session, NitramSession::Authenticated { .. });
+        self.backend
+            .insert(*ws_session_id, NitramSession::Anonymous)
+            .await;
+        tracing::debug!(sess = ws_session_id.to_string(), "deauth_ws_session");
+        was_authenticated
+    }
+
+    /// Downgrades the ws session to anonymous if its user session has expired,
+    /// and lets the client know so it can re-authenticate. Returns true if the
+    /// session expired. When it expires soon, the client is told once so it
+    /// can refresh it, see `NitramBuilder::set_session_expiring_warning`.
+    pub async fn expire_ws_session(&self, ws_session_id: &Uuid) -> bool {
+        let session = self.backend.get(ws_session_id).await;
+        self.expire_if_needed(ws_session_id, session.as_ref()).await
+    }
+
+    async fn expire_if_needed(
+        &self,
+        ws_session_id: &Uuid,
+        session: Option<&NitramSession>,
+    ) -> bool {
+        let expires_at = match session {
+            Some(NitramSession::Authenticated { user_session, .. }) => user_session.expires_at,
+            _ => return false,
+        };
+        let now = Utc::now();
+        if expires_at > now {
+            self.warn_if_expiring(ws_session_id, expires_at, now);
+            return false;
+        }
+        self.backend
+            .insert(*ws_session_id, NitramSession::Anonymous)
+            .await;
+        if let Some(outbox) = self.outboxes().get(ws_session_id) {
+            let _ = outbox.send(NitramServerMessage::session_expired());
+        }
+        tracing::debug!(sess = ws_session_id.to_string(), "Session expired");
+        true
+    }
+
+    fn warn_if_expiring(
+        &self,
+        ws_session_id: &Uuid,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) {
+        if self.session_expiring_warning.is_zero()
+            || expires_at - self.session_expiring_warning > now
+        {
+            return;
+        }
+        let outboxes = self.outboxes();
+        let Some(outbox) = outboxes.get(ws_session_id) else {
+            return;
+        };
+        let mut warnings = self.expiring_warnings_mut();
+        if warnings.get(ws_session_id) == Some(&expires_at) {
+            return;
+        }
+        warnings.insert(*ws_session_id, expires_at);
+        let _ = outbox.send(NitramServerMessage::session_expiring(expires_at));
+        tracing::debug!(sess = ws_session_id.to_string(), "Session expiring");
+    }
+
+    /// Sends a server message to every ws session registered to the topic.
+    /// Returns the number of sessions the message was delivered to.
+    pub async fn publish(&self, topic: &str, payload: Value) -> usize {
+        let mut delivered = 0;
+        for ws_session_id in self.backend.subscribers(topic).await {
+            if self.expire_ws_session(&ws_session_id).await {
+                continue;
+            }
+            if let Some(outbox) = self.outboxes().get(&ws_session_id) {
+                let server_message = NitramServerMessage {
+                    topic: topic.to_string(),
+                    payload: payload.clone(),
+                };
+                if outbox.send(server_message).is_ok() {
+                    delivered += 1;
+                }
+            }
+        }
+        tracing::debug!(topic = topic, delivered = delivered, "Published");
+        delivered
+    }
+
+    /// Sends the server message to every connected ws session.
+    pub async fn broadcast(&self, server_message: NitramServerMessage) -> usize {
+        self.outboxes()
+            .values()
+            .filter(|outbox| outbox.send(server_message.clone()).is_ok())
+            .count()
     }
+
+    /// Drops every ws session and outbox.
+    pub async fn clear(&self) {
+        self.outboxes_mut().clear();
+        self.protocol_versions_mut().clear();
+        self.encodings_mut().clear();
+        self.unsupported_protocol_versions_mut().clear();
+        self.expiring_warnings_mut().clear();
+        let cancellations = std::mem::take(&mut *self.cancellations_mut());
+        for token in cancellations.into_values().flat_map(HashMap::into_values) {
+            token.cancel();
+        }
+        self.backend.clear().await;
+    }
+}
+
+/// Resource to publish server messages from within handlers. Outside of
+/// handlers use `Nitram::publish`.
+#[derive(Clone, RpcResource)]
+pub struct NitramPublisher {
+    nitram_state: Arc<NitramState>,
+}
+
+impl NitramPublisher {
+    pub async fn publish(&self, topic: &str, payload: Value) -> usize {
+        self.nitram_state.publish(topic, payload).await
+    }
+}
+
+/// Resource of the request being handled, cancelled when the client sends
+/// `nitram_cancel` with its id or the websocket disconnects. The handler
+/// future is dropped then, long-running handlers can check it to stop work
+/// they spawned.
+#[derive(Clone, RpcResource)]
+pub struct RequestCancellation {
+    token: CancellationToken,
 }
 
+impl RequestCancellation {
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+}
+
+/// Key of a request id, the serialized id: JSON-RPC ids can be numbers, and
+/// `1` and `"1"` are different requests.
+pub(crate) fn request_key(id: &Value) -> Option<String> {
+    match id {
+        Value::String(_) | Value::Number(_) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Where the partial responses of stream handlers are sent, see
+/// `Nitram::send_streaming`.
+pub type Partials = mpsc::UnboundedSender<Value>;
+
 #[derive(Clone)]
 pub struct Nitram {
-    state: Arc<Mutex<NitramState>>,
-    rpc_router_public: Router,
-    rpc_router_private: Router,
-    rpc_router_server_messages: Router,
-    registered_public_handlers: Vec<String>,
-    registered_private_handlers: Vec<String>,
-    registered_server_message_handlers: Vec<String>,
+    pub(crate) state: Arc<NitramState>,
+    pub(crate) rpc_router_public: Router,
+    pub(crate) rpc_router_private: Router,
+    pub(crate) rpc_router_server_messages: Router,
+    pub(crate) registered_public_handlers: Vec<String>,
+    pub(crate) registered_private_handlers: Vec<String>,
+    pub(crate) registered_server_message_handlers: Vec<String>,
+    pub(crate) stream_handlers: StreamHandlers,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    /// Tasks spawned for the connected websockets
+    pub(crate) tasks: TaskTracker,
+    /// Requests being handled
+    pub(crate) in_flight: TaskTracker,
+    /// Cancelled on shutdown, cancels the tasks of every websocket
+    pub(crate) shutdown_token: CancellationToken,
+    pub(crate) draining: Arc<AtomicBool>,
     pub ping_interval_in_seconds: u64,
     pub server_messages_interval_in_millis: u64,
+    pub server_messages_polling: bool,
+    pub session_resume_grace_period_in_seconds: u64,
+    /// 0 to never send `nitram_session_expiring`
+    pub session_expiring_warning_in_seconds: u64,
+    pub max_concurrent_requests: usize,
+    /// Requests of a websocket waiting for one of `max_concurrent_requests`
+    pub max_pending_requests: usize,
+    /// Protocol version of the websockets that don't ask for one
+    pub default_protocol_version: ProtocolVersion,
+    /// Oldest protocol version still served
+    pub min_protocol_version: ProtocolVersion,
+    pub batch_execution: BatchExecution,
+    pub max_batch_size: usize,
+    /// 0 for no timeout
+    pub request_timeout_in_millis: u64,
+    /// Overrides of `request_timeout_in_millis`, by method
+    pub method_timeouts_in_millis: HashMap<String, u64>,
     pub timeout_in_seconds: u64,
     pub max_frame_size: usize,
 }
 
 impl Nitram {
+    /// Kept for the callers of 0.4, the other settings get the defaults of
+    /// `NitramBuilder`.
+    #[deprecated(note = "use `NitramBuilder` instead")]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         rpc_router_public: Router,
         rpc_router_private: Router,
@@ -64,37 +509,293 @@ impl Nitram {
         timeout_in_seconds: Option<u64>,
         max_frame_size: Option<usize>,
     ) -> Self {
-        // TODO: spawn a tokio task to read from live query streams
+        let defaults = NitramBuilder::default().build();
         Nitram {
-            state: Arc::new(Mutex::new(NitramState::new())),
             rpc_router_public,
             rpc_router_private,
             rpc_router_server_messages,
             registered_public_handlers,
             registered_private_handlers,
             registered_server_message_handlers,
-            ping_interval_in_seconds: ping_interval_in_seconds.unwrap_or(30),
-            server_messages_interval_in_millis: server_messages_interval_in_millis.unwrap_or(1000),
-            timeout_in_seconds: timeout_in_seconds.unwrap_or(90),
-            // TODO: is there a better frame size?
-            // increase the maximum allowed frame size to 128KiB and aggregate continuation frames
-            max_frame_size: max_frame_size.unwrap_or(128 * 1024),
+            ping_interval_in_seconds: ping_interval_in_seconds
+                .unwrap_or(defaults.ping_interval_in_seconds),
+            server_messages_interval_in_millis: server_messages_interval_in_millis
+                .unwrap_or(defaults.server_messages_interval_in_millis),
+            timeout_in_seconds: timeout_in_seconds.unwrap_or(defaults.timeout_in_seconds),
+            max_frame_size: max_frame_size.unwrap_or(defaults.max_frame_size),
+            ..defaults
         }
     }
 
+    /// How long a request can take: the timeout of its method, or the
+    /// default one, shortened by the deadline of the client. `None` for no
+    /// timeout.
+    fn request_timeout(&self, method: &str, deadline: Option<DateTime<Utc>>) -> Option<Duration> {
+        let timeout_in_millis = self
+            .method_timeouts_in_millis
+            .get(method)
+            .copied()
+            .unwrap_or(self.request_timeout_in_millis);
+        let timeout = (timeout_in_millis > 0).then(|| Duration::from_millis(timeout_in_millis));
+        let until_deadline =
+            deadline.map(|deadline| (deadline - Utc::now()).to_std().unwrap_or_default());
+        match (timeout, until_deadline) {
+            (Some(timeout), Some(until_deadline)) => Some(timeout.min(until_deadline)),
+            (timeout, until_deadline) => timeout.or(until_deadline),
+        }
+    }
+
+    fn session_resume_grace_period(&self) -> Duration {
+        Duration::from_secs(self.session_resume_grace_period_in_seconds)
+    }
+
     pub async fn insert(&self) -> Uuid {
         let uuid = Uuid::new_v4();
-        let mut state = self.state.lock().await;
-        state.ws_sessions.insert(uuid, NitramSession::Anonymous);
-        let count = state.ws_sessions.len();
+        self.state
+            .purge_detached_ws_sessions(self.session_resume_grace_period())
+            .await;
+        self.state
+            .backend
+            .insert(uuid, NitramSession::Anonymous)
+            .await;
+        self.state.issue_resume_token(uuid).await;
+        let count = self.state.backend.len().await;
         tracing::info!(sess = uuid.to_string(), count = count, "Inserted session");
         uuid
     }
 
+    /// Runs the `on_connect` hooks and returns the receiving end of the
+    /// channel where server messages for the ws session are pushed. The
+    /// channel is closed when the session is removed.
+    pub async fn connect(
+        &self,
+        ws_session_id: &Uuid,
+    ) -> mpsc::UnboundedReceiver<NitramServerMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.outboxes_mut().insert(*ws_session_id, tx);
+        let user_id = self
+            .state
+            .backend
+            .get(ws_session_id)
+            .await
+            .and_then(|session| session.user_id());
+        self.state.hooks.connect(*ws_session_id, user_id).await;
+        rx
+    }
+
+    /// Number of tasks still running for the websockets. All the tasks of a
+    /// websocket end when it is closed.
+    pub fn running_tasks(&self) -> usize {
+        self.tasks.len() + self.in_flight.len()
+    }
+
+    /// Stops accepting new websockets. Connected websockets are not affected.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        tracing::info!("Draining, new websockets are rejected");
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Gracefully shuts down:
+    /// 1. stops accepting new websockets (see `drain`)
+    /// 2. tells the connected clients with a `nitram_shutdown` server message
+    ///    to reconnect
+    /// 3. waits up to `deadline` for the requests in flight
+    /// 4. closes every websocket and clears the sessions
+    ///
+    /// Returns false if requests were still in flight at the deadline.
+    pub async fn shutdown(&self, deadline: Duration) -> bool {
+        self.drain();
+        let notified = self.state.broadcast(NitramServerMessage::shutdown()).await;
+        tracing::info!(notified = notified, "Shutting down");
+
+        self.in_flight.close();
+        let drained = tokio::time::timeout(deadline, self.in_flight.wait())
+            .await
+            .is_ok();
+        if !drained {
+            tracing::warn!(
+                in_flight = self.in_flight.len(),
+                "Requests still in flight at the shutdown deadline"
+            );
+        }
+
+        self.shutdown_token.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+        self.state.clear().await;
+        tracing::info!("Shut down");
+        drained
+    }
+
+    /// Sets the protocol version spoken with the websocket of the ws session.
+    pub fn set_protocol_version(&self, ws_session_id: &Uuid, version: ProtocolVersion) {
+        self.state
+            .protocol_versions_mut()
+            .insert(*ws_session_id, version);
+    }
+
+    pub fn protocol_version(&self, ws_session_id: &Uuid) -> ProtocolVersion {
+        self.state
+            .protocol_versions()
+            .get(ws_session_id)
+            .copied()
+            .unwrap_or(self.default_protocol_version)
+    }
+
+    pub fn supports_protocol_version(&self, version: ProtocolVersion) -> bool {
+        match (version.number(), self.min_protocol_version.number()) {
+            (Some(version), Some(min)) => version >= min,
+            _ => true,
+        }
+    }
+
+    pub fn supported_protocol_versions(&self) -> Vec<ProtocolVersion> {
+        ProtocolVersion::ALL
+            .into_iter()
+            .filter(|version| self.supports_protocol_version(*version))
+            .collect()
+    }
+
+    /// Sets the protocol version the client asked for, e.g. with the
+    /// `nitram_protocol` query parameter, or the default one. Returns false if
+    /// it is not supported: every request of the ws session then fails with
+    /// `upgrade_required`.
+    pub fn negotiate_protocol_version(
+        &self,
+        ws_session_id: &Uuid,
+        requested: Option<&str>,
+    ) -> bool {
+        let version = match requested {
+            Some(requested) => ProtocolVersion::parse(requested),
+            None => Some(self.default_protocol_version),
+        };
+        match version {
+            Some(version) if self.supports_protocol_version(version) => {
+                self.set_protocol_version(ws_session_id, version);
+                true
+            }
+            _ => {
+                let requested = requested
+                    .unwrap_or(self.default_protocol_version.as_str())
+                    .to_string();
+                tracing::info!(
+                    sess = ws_session_id.to_string(),
+                    requested = requested,
+                    "Unsupported protocol version"
+                );
+                self.state
+                    .unsupported_protocol_versions_mut()
+                    .insert(*ws_session_id, requested);
+                false
+            }
+        }
+    }
+
+    /// The error every request gets when the ws session asked for an
+    /// unsupported protocol version.
+    pub fn upgrade_required(&self, ws_session_id: &Uuid) -> Option<Value> {
+        let requested = self
+            .state
+            .unsupported_protocol_versions()
+            .get(ws_session_id)
+            .cloned()?;
+        let supported: Vec<_> = self
+            .supported_protocol_versions()
+            .iter()
+            .map(|version| version.as_str())
+            .collect();
+        Some(json!({ "requested": requested, "supported": supported }))
+    }
+
+    /// Sets the encoding of the frames of the websocket of the ws session.
+    pub fn set_encoding(&self, ws_session_id: &Uuid, encoding: Encoding) {
+        self.state.encodings_mut().insert(*ws_session_id, encoding);
+    }
+
+    pub fn encoding(&self, ws_session_id: &Uuid) -> Encoding {
+        self.state
+            .encodings()
+            .get(ws_session_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// What the client of the ws session needs to know when it connects.
+    pub async fn hello(&self, ws_session_id: &Uuid) -> NitramHello {
+        let user_id = self
+            .state
+            .backend
+            .get(ws_session_id)
+            .await
+            .and_then(|session| session.user_id());
+        NitramHello {
+            ws_session_id: *ws_session_id,
+            user_id,
+            protocol_version: self.protocol_version(ws_session_id).as_str().to_string(),
+            encoding: self.encoding(ws_session_id).as_str().to_string(),
+            server_time: Utc::now(),
+            ping_interval_in_seconds: self.ping_interval_in_seconds,
+            timeout_in_seconds: self.timeout_in_seconds,
+            max_frame_size: self.max_frame_size,
+            max_concurrent_requests: self.max_concurrent_requests,
+            max_batch_size: self.max_batch_size,
+            supported_protocol_versions: self
+                .supported_protocol_versions()
+                .iter()
+                .map(|version| version.as_str().to_string())
+                .collect(),
+            public: NitramCapabilities {
+                methods: self.registered_public_handlers.clone(),
+                topics: vec![],
+            },
+            private: NitramCapabilities {
+                methods: self.registered_private_handlers.clone(),
+                topics: self.registered_server_message_handlers.clone(),
+            },
+        }
+    }
+
+    /// Token to hand out to the client, so it can get this session back when
+    /// it reconnects.
+    pub async fn resume_token(&self, ws_session_id: &Uuid) -> Option<String> {
+        self.state.backend.resume_token(ws_session_id).await
+    }
+
+    /// Gives the new ws session the auth state, store and topic registrations
+    /// of the session that was handed `token`, if it was within the resume
+    /// grace period. Returns true if the session was resumed.
+    pub async fn resume(&self, token: &str, ws_session_id: &Uuid) -> bool {
+        let resumed = self
+            .state
+            .resume_ws_session(token, *ws_session_id, self.session_resume_grace_period())
+            .await;
+        tracing::info!(
+            sess = ws_session_id.to_string(),
+            resumed = resumed,
+            "Resume session"
+        );
+        resumed
+    }
+
+    /// Removes the ws session. When session resumption is enabled the session
+    /// is kept for the grace period, so a reconnecting client can resume it.
     pub async fn remove(&self, ws_session_id: &Uuid) {
-        let mut state = self.state.lock().await;
-        let removed = state.ws_sessions.remove(ws_session_id);
-        let count = state.ws_sessions.len();
+        let removed = if self.session_resume_grace_period_in_seconds > 0 {
+            self.state.detach_ws_session(ws_session_id).await;
+            self.state.backend.get(ws_session_id).await
+        } else {
+            self.state.remove_ws_session(ws_session_id).await
+        };
+        let user_id = removed.as_ref().and_then(|session| session.user_id());
+        self.state.hooks.disconnect(*ws_session_id, user_id).await;
+        self.state
+            .purge_detached_ws_sessions(self.session_resume_grace_period())
+            .await;
+        let count = self.state.backend.len().await;
         tracing::info!(
             sess = ws_session_id.to_string(),
             kind = format!("{:?}", removed),
@@ -103,25 +804,120 @@ impl Nitram {
         );
     }
 
+    /// Authenticates the ws session during the handshake with the
+    /// `Authenticator` of the builder, unless it is already authenticated,
+    /// e.g. resumed. Returns true if the session is authenticated.
+    pub async fn authenticate(&self, ws_session_id: &Uuid, req: &HttpRequest) -> bool {
+        let session = self.state.backend.get(ws_session_id).await;
+        if matches!(session, Some(NitramSession::Authenticated { .. })) {
+            return true;
+        }
+        let Some(authenticator) = &self.authenticator else {
+            return false;
+        };
+        let Some(user) = authenticator.authenticate(req).await else {
+            return false;
+        };
+        let user_session = UserSession {
+            id: *ws_session_id,
+            user_id: user.user_id,
+            expires_at: user.expires_at,
+        };
+        self.state
+            .auth_ws_session(*ws_session_id, user_session)
+            .await;
+        tracing::info!(
+            sess = ws_session_id.to_string(),
+            "Authenticated on handshake"
+        );
+        true
+    }
+
     /// This is meant to be used for testing. To authenticate a ws session you
     /// should use NitramInstance from within a handler.
-    pub async fn _auth_ws_session(&self, ws_session_id: Uuid, user_session: UserSession) -> () {
-        let mut state = self.state.lock().await;
-        state.auth_ws_session(ws_session_id, user_session);
-        tracing::debug!("auth_ws_session sessions: {:?}", state.ws_sessions);
+    /// `user_session.expires_at` can be set in the past to test expiry.
+    pub async fn _auth_ws_session(&self, ws_session_id: Uuid, user_session: UserSession) {
+        self.state
+            .auth_ws_session(ws_session_id, user_session)
+            .await;
+    }
+
+    /// Resets the ws session to anonymous, dropping its store and topic
+    /// registrations. Returns true if the session was authenticated.
+    pub async fn deauth(&self, ws_session_id: &Uuid) -> bool {
+        let was_authenticated = self.state.deauth_ws_session(ws_session_id).await;
+        tracing::info!(
+            sess = ws_session_id.to_string(),
+            was_authenticated = was_authenticated,
+            "Deauthenticated session"
+        );
+        was_authenticated
+    }
+
+    /// Extends the user session of the ws session, keeping its store and
+    /// topic registrations, see `WSSessionAnonymResource::refresh`.
+    pub async fn refresh(
+        &self,
+        ws_session_id: &Uuid,
+        user_id: &str,
+        expires_at: DateTime<Utc>,
+    ) -> bool {
+        self.state
+            .refresh_ws_session(ws_session_id, user_id, expires_at)
+            .await
+    }
+
+    /// Downgrades the ws session to anonymous if it expired, or tells the
+    /// client when it expires soon. Returns true if the session expired.
+    pub async fn check_expiry(&self, ws_session_id: &Uuid) -> bool {
+        self.state.expire_ws_session(ws_session_id).await
+    }
+
+    /// Cancels a request in flight of the ws session, like `nitram_cancel`.
+    /// The id is a string, or a number for JSON-RPC requests. Returns true if
+    /// the request was in flight.
+    pub fn cancel(&self, ws_session_id: &Uuid, request_id: &Value) -> bool {
+        request_key(request_id)
+            .is_some_and(|request_id| self.state.cancel_request(ws_session_id, &request_id))
+    }
+
+    /// Sends a server message right away to every ws session registered to
+    /// the topic with `nitram_topic_register`. Returns the number of sessions
+    /// the message was delivered to.
+    pub async fn publish(&self, topic: &str, payload: Value) -> usize {
+        self.state.publish(topic, payload).await
+    }
+
+    /// Returns a resource that can publish server messages, to be moved into
+    /// background tasks.
+    pub fn publisher(&self) -> NitramPublisher {
+        NitramPublisher {
+            nitram_state: self.state.clone(),
+        }
     }
 
     async fn is_auth(&self, ws_session_id: &Uuid) -> Result<UserPayload> {
-        let state = self.state.lock().await;
-        tracing::debug!("WS sessions: {:?}", state.ws_sessions);
-        match state.ws_sessions.get(ws_session_id) {
+        let session = self.state.backend.get(ws_session_id).await;
+        tracing::debug!(
+            sess = ws_session_id.to_string(),
+            "WS session: {:?}",
+            session
+        );
+        if self
+            .state
+            .expire_if_needed(ws_session_id, session.as_ref())
+            .await
+        {
+            return Err(Error::SessionExpired);
+        }
+        match session {
             Some(NitramSession::Authenticated {
                 user_session,
                 topics_registered: _,
                 store,
             }) => Ok(UserPayload {
-                user_session: user_session.clone(),
-                store: store.clone(),
+                user_session,
+                store,
             }),
             Some(NitramSession::Anonymous) => Err(Error::NotAuthorized),
             _ => Err(Error::NotAuthenticated),
@@ -133,15 +929,30 @@ impl Nitram {
         ws_session_id: &Uuid,
         msg: impl Into<String>,
         params: Value,
+        cancellation: RequestCancellation,
     ) -> Result<Value> {
         let msg: String = msg.into();
         tracing::debug!("Handling message: {}, with params: {}", msg, params);
 
+        // -- Logout
+        if msg == "nitram_logout" {
+            self.deauth(ws_session_id).await;
+            return Ok(json!(true));
+        }
+
+        // -- Cancellation of a request in flight
+        if msg == "nitram_cancel" {
+            let cancelled = params
+                .get("id")
+                .is_some_and(|request_id| self.cancel(ws_session_id, request_id));
+            return Ok(json!(cancelled));
+        }
+
         // -- Topic registration
         let is_register = msg == "nitram_topic_register";
         let is_deregister = msg == "nitram_topic_deregister";
         if is_register || is_deregister {
-            let topic = params.get("topic").map(|x| x.as_str()).flatten();
+            let topic = params.get("topic").and_then(|x| x.as_str());
             match topic {
                 Some(topic) => {
                     let params = match params.get("handler_params") {
@@ -153,25 +964,24 @@ impl Nitram {
                             Value::Null
                         }
                     };
-                    let mut state = self.state.lock().await;
-                    tracing::debug!("WS sessions: {:?}", state.ws_sessions);
-                    match state.ws_sessions.get_mut(ws_session_id) {
-                        Some(NitramSession::Authenticated {
-                            user_session: _,
-                            topics_registered,
-                            store: _,
-                        }) => {
-                            if is_register {
-                                topics_registered.insert(topic.to_string(), params);
-                            } else if is_deregister {
-                                topics_registered.remove(topic);
-                            }
-                            return Ok(json!(true));
-                        }
-                        _ => {
-                            tracing::error!("Invalid session state for topic registration");
-                        }
+                    if self.state.expire_ws_session(ws_session_id).await {
+                        return Err(Error::SessionExpired);
+                    }
+                    let registered = if is_register {
+                        self.state
+                            .backend
+                            .register_topic(ws_session_id, topic, params)
+                            .await
+                    } else {
+                        self.state
+                            .backend
+                            .deregister_topic(ws_session_id, topic)
+                            .await
+                    };
+                    if registered {
+                        return Ok(json!(true));
                     }
+                    tracing::error!("Invalid session state for topic registration");
                 }
                 None => {
                     tracing::error!("Missing topic for registration");
@@ -191,10 +1001,14 @@ impl Nitram {
         .try_into()?;
         let result = if is_public {
             let session_resource = WSSessionAnonymResource {
-                ws_session_id: ws_session_id.clone(),
+                ws_session_id: *ws_session_id,
                 nitram_state: self.state.clone(),
             };
-            let rpc_resources = Resources::builder().append(session_resource).build();
+            let rpc_resources = Resources::builder()
+                .append(session_resource)
+                .append(self.publisher())
+                .append(cancellation)
+                .build();
             self.rpc_router_public
                 .call_with_resources(rpc_request, rpc_resources)
                 .await
@@ -207,101 +1021,565 @@ impl Nitram {
             };
             let rpc_resources = Resources::builder()
                 .append(session_resource)
-                .append(user_payload.store)
+                .append(user_payload.store.clone())
+                .append(self.publisher())
+                .append(cancellation)
                 .build();
-            self.rpc_router_private
+            let result = self
+                .rpc_router_private
                 .call_with_resources(rpc_request, rpc_resources)
                 .await
                 .map(|r| r.value)
                 .map_err(|e| {
                     tracing::debug!("Error in private rpc router: {:?}", e.error);
                     e.into()
-                })
+                });
+            self.state
+                .backend
+                .save_store(ws_session_id, &user_payload.store)
+                .await;
+            result
         } else {
             Err(Error::MethodNotFound)
         };
         result
     }
 
-    pub async fn send(&self, payload: impl Into<ByteString>, ws_session_id: &Uuid) -> String {
-        let parsed = serde_json::from_str::<NitramRequest>(&payload.into());
-        let response = match parsed {
+    /// Runs a stream handler, passing each item to `on_item`. The result is
+    /// the number of items.
+    async fn handle_stream(
+        &self,
+        ws_session_id: &Uuid,
+        method: &str,
+        params: Value,
+        (handler, is_private): (BoxedStreamHandler, bool),
+        on_item: impl Fn(Value),
+        cancellation: RequestCancellation,
+    ) -> Result<Value> {
+        tracing::debug!("Streaming message: {}, with params: {}", method, params);
+        let mut rpc_resources = self
+            .stream_handlers
+            .resources
+            .clone()
+            .append(self.publisher())
+            .append(cancellation);
+        let user_payload = if is_private {
+            let user_payload = self.is_auth(ws_session_id).await?;
+            rpc_resources = rpc_resources
+                .append(WSSessionAuthedResource {
+                    user_id: user_payload.user_session.user_id.clone(),
+                })
+                .append(user_payload.store.clone());
+            Some(user_payload)
+        } else {
+            rpc_resources = rpc_resources.append(WSSessionAnonymResource {
+                ws_session_id: *ws_session_id,
+                nitram_state: self.state.clone(),
+            });
+            None
+        };
+        let call_error = |error| {
+            Error::RpcCallError(CallError {
+                id: Value::Null,
+                method: method.to_string(),
+                error,
+            })
+        };
+        let result = async {
+            let mut stream = handler(rpc_resources.build(), Some(params))
+                .await
+                .map_err(call_error)?;
+            let mut count = 0;
+            while let Some(item) = stream.next().await {
+                on_item(item.map_err(call_error)?);
+                count += 1;
+            }
+            Ok(json!(count))
+        }
+        .await;
+        if let Some(user_payload) = user_payload {
+            self.state
+                .backend
+                .save_store(ws_session_id, &user_payload.store)
+                .await;
+        }
+        result
+    }
+
+    /// `handle`, or `handle_stream` for the methods of a stream handler.
+    /// Requests with an id can be cancelled until they complete, see
+    /// `RequestCancellation`, and every request is cancelled when it times
+    /// out.
+    async fn handle_or_stream(
+        &self,
+        ws_session_id: &Uuid,
+        request_id: Option<&Value>,
+        method: &str,
+        params: Value,
+        deadline: Option<DateTime<Utc>>,
+        on_item: impl Fn(Value),
+    ) -> Result<Value> {
+        let timeout = self.request_timeout(method, deadline);
+        // The client already stopped waiting
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(Error::Timeout);
+        }
+        let request_id = request_id.and_then(request_key);
+        let token = match &request_id {
+            Some(request_id) => match self.state.start_request(ws_session_id, request_id) {
+                Some(token) => token,
+                None => return Err(Error::DuplicateRequestId),
+            },
+            None => CancellationToken::new(),
+        };
+        let cancellation = RequestCancellation {
+            token: token.clone(),
+        };
+        let handling = async {
+            match self.stream_handlers.get(method) {
+                Some(handler) => {
+                    self.handle_stream(
+                        ws_session_id,
+                        method,
+                        params,
+                        handler,
+                        on_item,
+                        cancellation,
+                    )
+                    .await
+                }
+                None => {
+                    self.handle(ws_session_id, method, params, cancellation)
+                        .await
+                }
+            }
+        };
+        let timed_out = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let result = tokio::select! {
+            biased;
+            _ = token.cancelled() => Err(Error::Cancelled),
+            _ = timed_out => {
+                token.cancel();
+                Err(Error::Timeout)
+            }
+            result = handling => result,
+        };
+        if let Some(request_id) = &request_id {
+            self.state.end_request(ws_session_id, request_id);
+        }
+        result
+    }
+
+    fn nice_error(error: Error) -> Nice {
+        match error {
+            Error::NotAuthorized => Nice::from(NiceMessage::NotAuthorized),
+            Error::NotAuthenticated => Nice::from(NiceMessage::NotAuthenticated),
+            Error::SessionExpired => Nice::from(NiceMessage::SessionExpired),
+            Error::Cancelled => Nice::from(NiceMessage::Cancelled),
+            Error::Timeout => Nice::from(NiceMessage::Timeout),
+            Error::DuplicateRequestId => Nice::from(NiceMessage::DuplicateId),
+            Error::RpcCallError(e) => match e.error {
+                rpc_router::Error::Handler(e) => {
+                    if let Some(method_error) = e.get::<MethodError>() {
+                        Nice::from(method_error)
+                    } else if let Some(ParamErrors(errors)) = e.get::<ParamErrors>() {
+                        Nice::with_data(NiceMessage::BadRequest, json!(errors))
+                    } else {
+                        Nice::from(NiceMessage::ServerError)
+                    }
+                }
+                rpc_router::Error::ParamsParsing(e) => Nice::with_data(
+                    NiceMessage::BadRequest,
+                    json!([ParamError::from_serde("", &e)]),
+                ),
+                rpc_router::Error::ParamsMissingButRequested => Nice::with_data(
+                    NiceMessage::BadRequest,
+                    json!([ParamError::new("params", "missing params")]),
+                ),
+                rpc_router::Error::MethodUnknown => Nice::from(NiceMessage::BadRequest),
+                _ => Nice::from(NiceMessage::ServerError),
+            },
+            Error::MethodNotFound => Nice::from(NiceMessage::BadRequest),
+            e => {
+                tracing::error!("Nitram unknown error: {}", e);
+                Nice::from(NiceMessage::ServerError)
+            }
+        }
+    }
+
+    /// JSON-RPC error code of an error, see `json_rpc`.
+    fn json_rpc_error(error: Error) -> JsonRpcError {
+        let code = match &error {
+            Error::MethodNotFound => json_rpc::METHOD_NOT_FOUND,
+            Error::Cancelled => json_rpc::REQUEST_CANCELLED,
+            Error::Timeout => json_rpc::REQUEST_TIMEOUT,
+            Error::DuplicateRequestId => json_rpc::INVALID_REQUEST,
+            Error::RpcCallError(e) => match &e.error {
+                rpc_router::Error::MethodUnknown => json_rpc::METHOD_NOT_FOUND,
+                rpc_router::Error::ParamsParsing(_)
+                | rpc_router::Error::ParamsMissingButRequested => json_rpc::INVALID_PARAMS,
+                rpc_router::Error::Handler(e) if e.get::<ParamErrors>().is_some() => {
+                    json_rpc::INVALID_PARAMS
+                }
+                _ => json_rpc::SERVER_ERROR,
+            },
+            _ => json_rpc::SERVER_ERROR,
+        };
+        JsonRpcError::from_nice(code, Self::nice_error(error))
+    }
+
+    /// Handles a JSON-RPC request, `None` for notifications.
+    async fn call_json_rpc(
+        &self,
+        request: Value,
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Option<JsonRpcResponse> {
+        let request = match JsonRpcRequest::parse(request) {
+            Ok(request) => request,
+            Err(response) => return Some(response),
+        };
+        // Items are sent as `nitram_partial` notifications, only for requests
+        // expecting a response
+        let on_item = |partial| {
+            if let (Some(partials), Some(id)) = (partials, &request.id) {
+                let params = json!({"id": id, "partial": partial});
+                let _ = partials.send(json_rpc::notification("nitram_partial", &params));
+            }
+        };
+        let result = self
+            .handle_or_stream(
+                ws_session_id,
+                request.id.as_ref(),
+                &request.method,
+                request.params,
+                None,
+                on_item,
+            )
+            .await;
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => JsonRpcResponse::result(id, result),
+            Err(e) => JsonRpcResponse::error(id, Self::json_rpc_error(e)),
+        })
+    }
+
+    /// Handles the requests of a batch, see `BatchExecution`.
+    async fn batch<T, Fut>(&self, requests: Vec<Value>, call: impl Fn(Value) -> Fut) -> Vec<T>
+    where
+        Fut: Future<Output = T>,
+    {
+        match self.batch_execution {
+            BatchExecution::Parallel => join_all(requests.into_iter().map(call)).await,
+            BatchExecution::Sequential => {
+                let mut responses = Vec::with_capacity(requests.len());
+                for request in requests {
+                    responses.push(call(request).await);
+                }
+                responses
+            }
+        }
+    }
+
+    async fn send_json_rpc(
+        &self,
+        request: Value,
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Option<Value> {
+        match request {
+            Value::Array(requests) if requests.is_empty() => Some(json!(JsonRpcResponse::error(
+                Value::Null,
+                JsonRpcError::new(json_rpc::INVALID_REQUEST, "invalid request"),
+            ))),
+            Value::Array(requests) => {
+                let too_large = requests.len() > self.max_batch_size;
+                let responses = self
+                    .batch(requests, |request| async move {
+                        if !too_large {
+                            return self.call_json_rpc(request, ws_session_id, partials).await;
+                        }
+                        match JsonRpcRequest::parse(request) {
+                            Ok(request) => request.id.map(|id| {
+                                let error = Nice::from(NiceMessage::BatchTooLarge);
+                                JsonRpcResponse::error(
+                                    id,
+                                    JsonRpcError::from_nice(json_rpc::INVALID_REQUEST, error),
+                                )
+                            }),
+                            Err(response) => Some(response),
+                        }
+                    })
+                    .await;
+                let responses: Vec<_> = responses.into_iter().flatten().collect();
+                (!responses.is_empty()).then(|| json!(responses))
+            }
+            request => self
+                .call_json_rpc(request, ws_session_id, partials)
+                .await
+                .map(|response| json!(response)),
+        }
+    }
+
+    async fn call(
+        &self,
+        request: std::result::Result<NitramRequest, InvalidRequest>,
+        ws_session_id: &Uuid,
+        protocol: ProtocolVersion,
+        partials: Option<&Partials>,
+    ) -> NitramResponse {
+        match request {
             Ok(req) => {
                 let id = req.id;
                 let method = req.method;
                 let params = req.params;
-                let res = match self.handle(ws_session_id, &method, params).await {
-                    Ok(res) => NitramResponse {
-                        id,
-                        response: res,
-                        ok: true,
-                        method,
-                    },
-                    Err(Error::NotAuthorized) => NitramResponse {
-                        id,
-                        response: Nice::from(NiceMessage::NotAuthorized).into(),
-                        ok: false,
-                        method,
-                    },
-                    Err(Error::NotAuthenticated) => NitramResponse {
-                        id,
-                        response: Nice::from(NiceMessage::NotAuthenticated).into(),
-                        ok: false,
-                        method,
-                    },
-                    Err(Error::RpcCallError(e)) => match e.error {
-                        rpc_router::Error::Handler(e) => NitramResponse {
-                            id,
-                            response: serde_json::to_value(e.get::<MethodError>())
-                                .unwrap_or_default(),
-                            ok: false,
-                            method,
-                        },
-                        rpc_router::Error::ParamsParsing(_)
-                        | rpc_router::Error::ParamsMissingButRequested => NitramResponse {
-                            id,
-                            response: Nice::from(NiceMessage::BadRequest).into(),
-                            ok: false,
-                            method,
-                        },
-                        rpc_router::Error::MethodUnknown => NitramResponse {
-                            id,
-                            response: Nice::from(NiceMessage::BadRequest).into(),
-                            ok: false,
-                            method,
-                        },
-                        _ => NitramResponse {
-                            id,
-                            response: Nice::from(NiceMessage::ServerError).into(),
-                            ok: false,
-                            method,
-                        },
-                    },
-                    Err(Error::MethodNotFound) => NitramResponse {
-                        id,
-                        response: Nice::from(NiceMessage::BadRequest).into(),
-                        ok: false,
-                        method,
-                    },
-                    Err(e) => {
-                        tracing::error!("Nitram unknown error: {}", e);
-                        NitramResponse {
-                            id,
-                            response: Nice::from(NiceMessage::ServerError).into(),
-                            ok: false,
-                            method,
-                        }
+                let deadline = req.deadline;
+                let on_item = |partial| {
+                    if let Some(partials) = partials {
+                        let _ = partials.send(json!(NitramPartialResponse {
+                            id: id.clone(),
+                            method: method.clone(),
+                            partial,
+                        }));
                     }
                 };
-                res
+                let request_id = Value::String(id.clone());
+                let result = self
+                    .handle_or_stream(
+                        ws_session_id,
+                        Some(&request_id),
+                        &method,
+                        params,
+                        deadline,
+                        on_item,
+                    )
+                    .await;
+                Self::nitram_response(id, method, result, protocol)
             }
+            Err(invalid) => Self::invalid_response(invalid, protocol),
+        }
+    }
+
+    fn nitram_response(
+        id: String,
+        method: String,
+        result: Result<Value>,
+        protocol: ProtocolVersion,
+    ) -> NitramResponse {
+        match result {
+            Ok(res) => NitramResponse {
+                id,
+                response: res,
+                ok: true,
+                method,
+            },
+            Err(e) => NitramResponse {
+                id,
+                response: Self::nice_error(e).into_value(protocol),
+                ok: false,
+                method,
+            },
+        }
+    }
+
+    /// Echoes what could be read, so the client can reject the request.
+    fn invalid_response(invalid: InvalidRequest, protocol: ProtocolVersion) -> NitramResponse {
+        NitramResponse {
+            id: invalid.id.unwrap_or_else(|| "_err".to_string()),
+            method: invalid.method.unwrap_or_else(|| "_err".to_string()),
+            response: (*invalid.error).into_value(protocol),
+            ok: false,
+        }
+    }
+
+    /// Responds to a request with `result` without handling it, e.g. to a
+    /// request cancelled while it waited for a permit, see `ws`. `None` for
+    /// JSON-RPC notifications.
+    pub(crate) fn answer(
+        &self,
+        request: Value,
+        ws_session_id: &Uuid,
+        result: Result<Value>,
+    ) -> Option<Value> {
+        let protocol = self.protocol_version(ws_session_id);
+        if protocol == ProtocolVersion::JsonRpc {
+            let id = request.get("id").cloned()?;
+            return Some(json!(match result {
+                Ok(result) => JsonRpcResponse::result(id, result),
+                Err(e) => JsonRpcResponse::error(id, Self::json_rpc_error(e)),
+            }));
+        }
+        let check = self.check_protocol_version(ws_session_id);
+        let response = match check(NitramRequest::from_value(request)) {
+            Ok(request) => Self::nitram_response(request.id, request.method, result, protocol),
+            Err(invalid) => Self::invalid_response(invalid, protocol),
+        };
+        Some(json!(response))
+    }
+
+    /// Handles a frame and returns the response, in the protocol of the ws
+    /// session. A frame with an array of requests (a batch) gets an array of
+    /// responses. Empty when there is nothing to answer, i.e. JSON-RPC
+    /// notifications.
+    ///
+    /// The items of stream handlers are dropped, see `send_streaming`.
+    pub async fn send(&self, payload: impl Into<ByteString>, ws_session_id: &Uuid) -> String {
+        self.respond_text(&payload.into(), ws_session_id, None)
+            .await
+            .map(|response| response.to_string())
+            .unwrap_or_default()
+    }
+
+    /// `send`, with the items of stream handlers sent to `partials` as
+    /// partial responses before the response is returned.
+    pub async fn send_streaming(
+        &self,
+        payload: impl Into<ByteString>,
+        ws_session_id: &Uuid,
+        partials: &Partials,
+    ) -> String {
+        self.respond_text(&payload.into(), ws_session_id, Some(partials))
+            .await
+            .map(|response| response.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Handles a text frame, see `respond`.
+    async fn respond_text(
+        &self,
+        payload: &str,
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Option<Value> {
+        match serde_json::from_str(payload) {
+            Ok(request) => self.respond(request, ws_session_id, partials).await,
+            Err(_) => self.respond_invalid(payload, ws_session_id, partials).await,
+        }
+    }
+
+    /// Handles a decoded frame and returns the response, `None` when there is
+    /// nothing to answer. The response is encoded by the caller.
+    pub(crate) async fn respond(
+        &self,
+        request: Value,
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Option<Value> {
+        let protocol = self.protocol_version(ws_session_id);
+        if protocol == ProtocolVersion::JsonRpc {
+            return self.send_json_rpc(request, ws_session_id, partials).await;
+        }
+        let check = self.check_protocol_version(ws_session_id);
+        if let Value::Array(requests) = request {
+            let too_large = requests.len() > self.max_batch_size;
+            let responses = self
+                .batch(requests, |request| async {
+                    let mut request = check(NitramRequest::from_value(request));
+                    if too_large {
+                        request = Err(InvalidRequest::reject(request, NiceMessage::BatchTooLarge));
+                    }
+                    self.call(request, ws_session_id, protocol, partials).await
+                })
+                .await;
+            return Some(json!(responses));
+        }
+        let response = self
+            .call(
+                check(NitramRequest::from_value(request)),
+                ws_session_id,
+                protocol,
+                partials,
+            )
+            .await;
+        Some(json!(response))
+    }
+
+    /// Answers a frame that is not valid JSON, or can't be decoded, with the
+    /// invalid JSON error of the protocol.
+    pub(crate) async fn respond_invalid(
+        &self,
+        payload: &str,
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Option<Value> {
+        let protocol = self.protocol_version(ws_session_id);
+        if protocol == ProtocolVersion::JsonRpc {
+            return Some(json!(JsonRpcResponse::error(
+                Value::Null,
+                JsonRpcError::new(json_rpc::PARSE_ERROR, "parse error"),
+            )));
+        }
+        let check = self.check_protocol_version(ws_session_id);
+        let response = self
+            .call(
+                check(NitramRequest::parse(payload)),
+                ws_session_id,
+                protocol,
+                partials,
+            )
+            .await;
+        Some(json!(response))
+    }
+
+    /// Clients of an unsupported protocol version get an error for every
+    /// request.
+    fn check_protocol_version(
+        &self,
+        ws_session_id: &Uuid,
+    ) -> impl Fn(
+        std::result::Result<NitramRequest, InvalidRequest>,
+    ) -> std::result::Result<NitramRequest, InvalidRequest> {
+        let upgrade_required = self.upgrade_required(ws_session_id);
+        move |request| match &upgrade_required {
+            Some(data) => Err(InvalidRequest::reject(
+                request,
+                Nice::with_data(NiceMessage::UpgradeRequired, data.clone()),
+            )),
+            None => request,
+        }
+    }
+
+    /// `send` for frames in the encoding of the ws session, see `Encoding`.
+    /// Empty when there is nothing to answer.
+    pub async fn send_encoded(&self, payload: &[u8], ws_session_id: &Uuid) -> Vec<u8> {
+        self.respond_encoded(payload, ws_session_id, None).await
+    }
+
+    /// `send_streaming` for frames in the encoding of the ws session. The
+    /// partial responses are left for the caller to encode.
+    pub async fn send_encoded_streaming(
+        &self,
+        payload: &[u8],
+        ws_session_id: &Uuid,
+        partials: &Partials,
+    ) -> Vec<u8> {
+        self.respond_encoded(payload, ws_session_id, Some(partials))
+            .await
+    }
+
+    async fn respond_encoded(
+        &self,
+        payload: &[u8],
+        ws_session_id: &Uuid,
+        partials: Option<&Partials>,
+    ) -> Vec<u8> {
+        let encoding = self.encoding(ws_session_id);
+        let response = match encoding.decode(payload) {
+            Ok(request) => self.respond(request, ws_session_id, partials).await,
             Err(_) => {
-                let res = NitramResponse::error("Invalid message, check API");
-                res
+                let payload = String::from_utf8_lossy(payload);
+                self.respond_invalid(&payload, ws_session_id, partials)
+                    .await
             }
         };
-
-        serde_json::to_string(&response).unwrap_or_default()
+        response
+            .map(|response| encoding.encode(&response))
+            .unwrap_or_default()
     }
 
     pub async fn get_server_messages_for_session(
@@ -309,74 +1587,76 @@ impl Nitram {
         ws_session_id: &Uuid,
     ) -> Vec<NitramServerMessage> {
         let mut server_messages: Vec<NitramServerMessage> = vec![];
-        let state = self.state.lock().await;
-        let session = state.ws_sessions.get(ws_session_id);
-        if let Some(session) = session {
-            if let NitramSession::Authenticated {
-                user_session,
-                topics_registered,
-                store,
-            } = session
-            {
-                // Call registered server message handlers
-                for topic in &self.registered_server_message_handlers {
-                    match topics_registered.contains_key(topic) {
-                        true => {
-                            let rpc_request = Request {
-                                id: "server-message".into(),
-                                method: topic.clone(),
-                                params: topics_registered.get(topic).cloned(),
-                            };
-                            let session_resource = WSSessionAuthedResource {
-                                user_id: user_session.user_id.clone(),
-                            };
-                            let rpc_resources = Resources::builder()
-                                .append(session_resource)
-                                .append(store.clone())
-                                .build();
-
-                            let result = self
-                                .rpc_router_server_messages
-                                .call_with_resources(rpc_request, rpc_resources)
-                                .await
-                                .map(|r| r.value);
-                            match result {
-                                Ok(result) => {
-                                    server_messages.push(NitramServerMessage {
-                                        topic: topic.clone(),
-                                        payload: result,
-                                    });
-                                }
-                                Err(e) => match &e.error {
-                                    rpc_router::Error::Handler(e) => {
-                                        let method_error = e.get::<MethodError>();
-                                        match method_error {
-                                            Some(MethodError::NoResponse) => {
-                                                // This is not a real error, so we don't log it
-                                            }
-                                            _ => {
-                                                tracing::error!(
-                                                    "Error calling server message handler: {}",
-                                                    e
-                                                );
-                                            }
+        if self.state.expire_ws_session(ws_session_id).await {
+            // The client was already notified through the outbox
+            return server_messages;
+        }
+        let session = self.state.backend.get(ws_session_id).await;
+        if let Some(NitramSession::Authenticated {
+            user_session,
+            topics_registered,
+            store,
+        }) = session
+        {
+            // Call registered server message handlers
+            for topic in &self.registered_server_message_handlers {
+                match topics_registered.contains_key(topic) {
+                    true => {
+                        let rpc_request = Request {
+                            id: "server-message".into(),
+                            method: topic.clone(),
+                            params: topics_registered.get(topic).cloned(),
+                        };
+                        let session_resource = WSSessionAuthedResource {
+                            user_id: user_session.user_id.clone(),
+                        };
+                        let rpc_resources = Resources::builder()
+                            .append(session_resource)
+                            .append(store.clone())
+                            .append(self.publisher())
+                            .build();
+
+                        let result = self
+                            .rpc_router_server_messages
+                            .call_with_resources(rpc_request, rpc_resources)
+                            .await
+                            .map(|r| r.value);
+                        match result {
+                            Ok(result) => {
+                                server_messages.push(NitramServerMessage {
+                                    topic: topic.clone(),
+                                    payload: result,
+                                });
+                            }
+                            Err(e) => match &e.error {
+                                rpc_router::Error::Handler(e) => {
+                                    let method_error = e.get::<MethodError>();
+                                    match method_error {
+                                        Some(MethodError::NoResponse) => {
+                                            // This is not a real error, so we don't log it
+                                        }
+                                        _ => {
+                                            tracing::error!(
+                                                "Error calling server message handler: {}",
+                                                e
+                                            );
                                         }
                                     }
-                                    _ => {
-                                        tracing::error!(
-                                            "Error calling server message handler: {}",
-                                            e
-                                        );
-                                    }
-                                },
-                            }
-                        }
-                        false => {
-                            // Skip if user is not registered to the topic
+                                }
+                                _ => {
+                                    tracing::error!("Error calling server message handler: {}", e);
+                                }
+                            },
                         }
                     }
+                    false => {
+                        // Skip if user is not registered to the topic
+                    }
                 }
             }
+            if !topics_registered.is_empty() {
+                self.state.backend.save_store(ws_session_id, &store).await;
+            }
         }
         server_messages
     }
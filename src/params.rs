@@ -0,0 +1,166 @@
+use rpc_router::HandlerError;
+use serde::{de::DeserializeOwned, Serialize};
+use ts_rs::TS;
+
+pub use rpc_router::Result;
+pub use serde_json::Value;
+
+/// A param that failed to deserialize or validate. Bad request responses
+/// carry the list of them as data.
+#[derive(Clone, Debug, PartialEq, Serialize, TS)]
+#[ts(export, export_to = "Nitram.ts")]
+pub struct ParamError {
+    /// e.g. `params.channel`
+    pub path: String,
+    pub message: String,
+}
+
+impl ParamError {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// From a serde error at `path` (relative to the params). serde reports
+    /// missing fields on their parent, so they are moved to the field.
+    pub fn from_serde(path: &str, error: &impl std::fmt::Display) -> Self {
+        let message = error.to_string();
+        let path = match path {
+            "" | "." => "params".to_string(),
+            path => format!("params.{}", path),
+        };
+        match message
+            .strip_prefix("missing field `")
+            .and_then(|field| field.strip_suffix('`'))
+        {
+            Some(field) => Self::new(format!("{}.{}", path, field), "missing field"),
+            None => Self::new(path, message),
+        }
+    }
+}
+
+/// Every param error of a request, returned by `parse`.
+#[derive(Debug)]
+pub struct ParamErrors(pub Vec<ParamError>);
+
+/// Checks the params after deserializing them. `nitram_handler!` implements
+/// it from the rules declared on the params.
+pub trait Validate {
+    /// Every violation, not only the first one.
+    fn validate(&self) -> Vec<ParamError> {
+        vec![]
+    }
+}
+
+/// Deserializes and validates params, reporting the path of the failing
+/// field. To use it with params that are not declared with
+/// `nitram_handler!`, call it from `IntoParams::into_params`.
+pub fn parse<T: DeserializeOwned + Validate>(value: Option<Value>) -> Result<T> {
+    let value = value.ok_or(rpc_router::Error::ParamsMissingButRequested)?;
+    let params: T = serde_path_to_error::deserialize(value).map_err(|e| {
+        invalid(vec![ParamError::from_serde(
+            &e.path().to_string(),
+            e.inner(),
+        )])
+    })?;
+    let errors = params.validate();
+    if !errors.is_empty() {
+        return Err(invalid(errors));
+    }
+    Ok(params)
+}
+
+fn invalid(errors: Vec<ParamError>) -> rpc_router::Error {
+    rpc_router::Error::Handler(HandlerError::new(ParamErrors(errors)))
+}
+
+/// Validation rules for `nitram_handler!`. Each rule returns the reason the
+/// value is invalid.
+pub mod rules {
+    use regex::Regex;
+    use std::{
+        collections::HashMap,
+        fmt::Debug,
+        ops::RangeBounds,
+        sync::{OnceLock, PoisonError, RwLock},
+    };
+
+    pub trait Length {
+        fn length(&self) -> Option<usize>;
+    }
+
+    impl Length for String {
+        fn length(&self) -> Option<usize> {
+            Some(self.chars().count())
+        }
+    }
+
+    impl<T> Length for Vec<T> {
+        fn length(&self) -> Option<usize> {
+            Some(self.len())
+        }
+    }
+
+    /// Missing optional values are valid.
+    impl<T: Length> Length for Option<T> {
+        fn length(&self) -> Option<usize> {
+            self.as_ref().and_then(Length::length)
+        }
+    }
+
+    /// Number of characters of a string, or items of a vec.
+    pub fn length(
+        value: &impl Length,
+        range: impl RangeBounds<usize> + Debug,
+    ) -> Result<(), String> {
+        match value.length() {
+            Some(length) if !range.contains(&length) => {
+                Err(format!("length must be in {:?}", range))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn range<T: PartialOrd>(
+        value: &T,
+        range: impl RangeBounds<T> + Debug,
+    ) -> Result<(), String> {
+        match range.contains(value) {
+            true => Ok(()),
+            false => Err(format!("must be in {:?}", range)),
+        }
+    }
+
+    /// The patterns are compiled once.
+    pub fn regex(value: &impl AsRef<str>, pattern: &'static str) -> Result<(), String> {
+        static REGEXES: OnceLock<RwLock<HashMap<&'static str, Regex>>> = OnceLock::new();
+        let regexes = REGEXES.get_or_init(Default::default);
+        let cached = regexes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(pattern)
+            .map(|regex| regex.is_match(value.as_ref()));
+        let is_match = match cached {
+            Some(is_match) => is_match,
+            None => {
+                let regex = Regex::new(pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+                let is_match = regex.is_match(value.as_ref());
+                regexes
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(pattern, regex);
+                is_match
+            }
+        };
+        match is_match {
+            true => Ok(()),
+            false => Err(format!("must match {}", pattern)),
+        }
+    }
+
+    pub fn custom<T>(value: &T, check: fn(&T) -> Result<(), String>) -> Result<(), String> {
+        check(value)
+    }
+}
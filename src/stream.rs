@@ -0,0 +1,92 @@
+use futures_util::{Stream, StreamExt};
+use rpc_router::{FromResources, IntoHandlerError, IntoParams, Resources, ResourcesBuilder};
+use serde::Serialize;
+use serde_json::Value;
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use crate::error::{MethodError, MethodResult};
+
+/// What stream handlers return, e.g. with `Box::pin(stream)`. Each item is
+/// sent as a partial response as soon as it is ready, and an error item ends
+/// the stream with an error response.
+pub type NitramStream<T> = Pin<Box<dyn Stream<Item = MethodResult<T>> + Send>>;
+
+pub(crate) type ValueStream = Pin<Box<dyn Stream<Item = rpc_router::Result<Value>> + Send>>;
+type StreamFuture = Pin<Box<dyn Future<Output = rpc_router::Result<ValueStream>> + Send>>;
+pub(crate) type BoxedStreamHandler =
+    Arc<dyn Fn(Resources, Option<Value>) -> StreamFuture + Send + Sync>;
+
+/// A handler registered with `NitramBuilder::add_public_stream_handler` or
+/// `add_private_stream_handler`: an async fn taking resources, then the
+/// params, like the other handlers, and returning a stream of items.
+pub trait StreamHandler<T, P>: Clone + Send + Sync + 'static {
+    fn call(self, resources: Resources, params: Option<Value>) -> StreamFuture;
+}
+
+pub(crate) fn boxed<H, T, P>(handler: H) -> BoxedStreamHandler
+where
+    H: StreamHandler<T, P>,
+{
+    Arc::new(move |resources, params| handler.clone().call(resources, params))
+}
+
+fn handler_error(error: MethodError) -> rpc_router::Error {
+    rpc_router::Error::Handler(error.into_handler_error())
+}
+
+macro_rules! impl_stream_handler {
+    ( $($T:ident),* ) => {
+        impl<F, Fut, $($T,)* P, S, I> StreamHandler<($($T,)*), (P,)> for F
+        where
+            F: FnOnce($($T,)* P) -> Fut + Clone + Send + Sync + 'static,
+            $( $T: FromResources + Clone + Send + Sync + 'static, )*
+            P: IntoParams + Send + Sync + 'static,
+            Fut: Future<Output = MethodResult<S>> + Send + 'static,
+            S: Stream<Item = MethodResult<I>> + Send + 'static,
+            I: Serialize,
+        {
+            #[allow(unused)] // resources are unused without resource arguments
+            fn call(self, resources: Resources, params: Option<Value>) -> StreamFuture {
+                Box::pin(async move {
+                    let params = P::into_params(params)?;
+                    let stream = self($( $T::from_resources(&resources)?, )* params)
+                        .await
+                        .map_err(handler_error)?;
+                    let stream: ValueStream = Box::pin(stream.map(|item| {
+                        let item = item.map_err(handler_error)?;
+                        serde_json::to_value(item).map_err(rpc_router::Error::HandlerResultSerialize)
+                    }));
+                    Ok(stream)
+                })
+            }
+        }
+    };
+}
+
+impl_stream_handler!();
+impl_stream_handler!(T1);
+impl_stream_handler!(T1, T2);
+impl_stream_handler!(T1, T2, T3);
+impl_stream_handler!(T1, T2, T3, T4);
+
+/// The stream handlers registered on the builder, by method name.
+#[derive(Clone, Default)]
+pub struct StreamHandlers {
+    pub(crate) public: HashMap<String, BoxedStreamHandler>,
+    pub(crate) private: HashMap<String, BoxedStreamHandler>,
+    /// The resources registered with `NitramBuilder::add_resource`, the ws
+    /// session resources are appended on every call
+    pub(crate) resources: ResourcesBuilder,
+}
+
+impl StreamHandlers {
+    /// The handler of `method`, and whether it is private.
+    pub(crate) fn get(&self, method: &str) -> Option<(BoxedStreamHandler, bool)> {
+        if let Some(handler) = self.public.get(method) {
+            return Some((handler.clone(), false));
+        }
+        self.private
+            .get(method)
+            .map(|handler| (handler.clone(), true))
+    }
+}
@@ -1,19 +1,201 @@
-use actix_web::{web, HttpRequest, HttpResponse};
-use actix_ws::AggregatedMessage;
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Session};
+use serde_json::{json, Value};
 use std::{
+    collections::{HashMap, VecDeque},
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
-use crate::Nitram;
+use crate::encoding::Encoding;
+use crate::error::Error;
+use crate::messages::NitramServerMessage;
+use crate::{request_key, Nitram};
+
+/// Sends a server message frame in the encoding of the websocket.
+async fn send_value(
+    session: &mut Session,
+    value: Value,
+    encoding: Encoding,
+) -> Result<(), actix_ws::Closed> {
+    match encoding.is_binary() {
+        true => session.binary(encoding.encode(&value)).await,
+        false => session.text(value.to_string()).await,
+    }
+}
+
+/// Sends the partial responses of stream handlers until the request is
+/// answered, in JSON text frames or binary frames in `encoding`.
+async fn forward_partials(
+    mut session: Session,
+    mut partials: mpsc::UnboundedReceiver<Value>,
+    encoding: Option<Encoding>,
+) {
+    while let Some(partial) = partials.recv().await {
+        let sent = match encoding {
+            Some(encoding) => session.binary(encoding.encode(&partial)).await,
+            None => session.text(partial.to_string()).await,
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+/// A request frame, decoded once when it is read.
+struct Frame {
+    /// The request, or the payload of a frame that could not be decoded
+    request: Result<Value, String>,
+    /// Binary frames are answered in the encoding of the websocket
+    encoding: Option<Encoding>,
+}
+
+impl Frame {
+    fn text(text: &str) -> Self {
+        Frame {
+            request: serde_json::from_str(text).map_err(|_| text.to_string()),
+            encoding: None,
+        }
+    }
+
+    fn binary(bytes: &[u8], encoding: Encoding) -> Self {
+        Frame {
+            request: encoding
+                .decode(bytes)
+                .map_err(|_| String::from_utf8_lossy(bytes).into_owned()),
+            encoding: Some(encoding),
+        }
+    }
+
+    fn id(&self) -> Option<&Value> {
+        self.request.as_ref().ok()?.get("id")
+    }
+
+    /// Id of the request a `nitram_cancel` frame cancels.
+    fn cancelled_id(&self) -> Option<&Value> {
+        let request = self.request.as_ref().ok()?;
+        if request.get("method").and_then(Value::as_str) != Some("nitram_cancel") {
+            return None;
+        }
+        request.get("params")?.get("id")
+    }
+
+    /// Whether the frame is a `nitram_cancel` request, or a batch with one.
+    /// Those are dispatched right away, as the requests they cancel may hold
+    /// every permit.
+    fn is_cancel(&self) -> bool {
+        let is_cancel = |request: &Value| {
+            request.get("method").and_then(Value::as_str) == Some("nitram_cancel")
+        };
+        match &self.request {
+            Ok(Value::Array(requests)) => requests.iter().any(is_cancel),
+            Ok(request) => is_cancel(request),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Handles a request frame and sends the response in the same kind of frame,
+/// after the partial responses of stream handlers.
+async fn respond(nitram: &Nitram, session: &mut Session, frame: Frame, session_id: &Uuid) {
+    let (partials, receiver) = mpsc::unbounded_channel();
+    let (response, _) = tokio::join!(
+        async move {
+            match frame.request {
+                Ok(request) => nitram.respond(request, session_id, Some(&partials)).await,
+                Err(payload) => {
+                    nitram
+                        .respond_invalid(&payload, session_id, Some(&partials))
+                        .await
+                }
+            }
+        },
+        forward_partials(session.clone(), receiver, frame.encoding),
+    );
+    // Nothing to answer to JSON-RPC notifications
+    if let Some(response) = response {
+        send_response(session, response, frame.encoding).await;
+    }
+}
+
+/// Sends a response in a binary frame in `encoding`, or in a text frame.
+async fn send_response(session: &mut Session, response: Value, encoding: Option<Encoding>) {
+    let _ = match encoding {
+        Some(encoding) => session.binary(encoding.encode(&response)).await,
+        None => session.text(response.to_string()).await,
+    };
+}
+
+/// Responds to a frame with `result` without handling it.
+async fn answer(
+    nitram: &Nitram,
+    session: &mut Session,
+    frame: Frame,
+    session_id: &Uuid,
+    result: crate::error::Result<Value>,
+) {
+    let Ok(request) = frame.request else {
+        return;
+    };
+    if let Some(response) = nitram.answer(request, session_id, result) {
+        send_response(session, response, frame.encoding).await;
+    }
+}
+
+/// Takes the frames still waiting for a permit that a `nitram_cancel` frame
+/// cancels.
+fn take_cancelled(pending: &mut VecDeque<Frame>, frame: &Frame) -> VecDeque<Frame> {
+    let Some(cancelled_id) = frame.cancelled_id().and_then(request_key) else {
+        return VecDeque::new();
+    };
+    let (cancelled, kept) = pending
+        .drain(..)
+        .partition(|pending| pending.id().and_then(request_key).as_ref() == Some(&cancelled_id));
+    *pending = kept;
+    cancelled
+}
+
+/// How many `nitram_cancel` frames of a websocket can be handled at once
+/// outside of `max_concurrent_requests`. The others wait for a permit like
+/// any request.
+const MAX_CONCURRENT_CANCELS: usize = 4;
+
+/// Handles a request frame in a task of its own, which holds `permit` until
+/// the request is answered or the websocket closes.
+fn dispatch(
+    nitram: &Nitram,
+    session: &Session,
+    cancel: &CancellationToken,
+    frame: Frame,
+    session_id: Uuid,
+    permit: OwnedSemaphorePermit,
+) {
+    let nitram_for_request = nitram.clone();
+    let mut session = session.clone();
+    let cancel = cancel.clone();
+    nitram.in_flight.spawn_local(async move {
+        tokio::select! {
+            _ = cancel.cancelled() => {}
+            _ = respond(&nitram_for_request, &mut session, frame, &session_id) => {}
+        }
+        drop(permit);
+    });
+}
 
 pub async fn handler(
     req: HttpRequest,
     body: web::Payload,
     nitram: web::Data<Nitram>,
 ) -> std::result::Result<HttpResponse, actix_web::Error> {
-    let (response, mut session, stream) = actix_ws::handle(&req, body)?;
+    if nitram.is_draining() {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+
+    let (response, mut session, stream) =
+        actix_ws::handle_with_protocols(&req, body, &Encoding::SUBPROTOCOLS)?;
 
     let mut stream = stream
         .max_frame_size(nitram.max_frame_size)
@@ -21,17 +203,72 @@ pub async fn handler(
 
     let session_id = nitram.insert().await;
 
+    // -- Session resumption
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|q| q.into_inner())
+        .unwrap_or_default();
+    let protocol_supported = nitram.negotiate_protocol_version(
+        &session_id,
+        query.get("nitram_protocol").map(String::as_str),
+    );
+    let subprotocol = response
+        .headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|subprotocol| subprotocol.to_str().ok());
+    if let Some(encoding) = subprotocol
+        .and_then(Encoding::from_subprotocol)
+        .or_else(|| {
+            query
+                .get("nitram_encoding")
+                .and_then(|e| Encoding::parse(e))
+        })
+    {
+        nitram.set_encoding(&session_id, encoding);
+    }
+    let resumed = match query.get("nitram_resume") {
+        Some(token) => nitram.resume(token, &session_id).await,
+        None => false,
+    };
+
+    // -- Authentication from the upgrade request, so the first requests
+    // don't race an `Authenticate` call
+    nitram.authenticate(&session_id, &req).await;
+
+    let encoding = nitram.encoding(&session_id);
+    let protocol = nitram.protocol_version(&session_id);
+    let hello = NitramServerMessage::hello(nitram.hello(&session_id).await);
+    let _ = send_value(&mut session, hello.to_json(protocol), encoding).await;
+    if !protocol_supported {
+        if let Some(payload) = nitram.upgrade_required(&session_id) {
+            let server_message = NitramServerMessage::upgrade_required(payload);
+            let _ = send_value(&mut session, server_message.to_json(protocol), encoding).await;
+        }
+    }
+    if let Some(token) = nitram.resume_token(&session_id).await {
+        let server_message = NitramServerMessage::resume_token(token, resumed);
+        let _ = send_value(&mut session, server_message.to_json(protocol), encoding).await;
+    }
+
+    // -- Task lifecycle: when any of the tasks of this websocket ends, the
+    // others are cancelled. All of them are cancelled on shutdown.
+    let cancel = nitram.shutdown_token.child_token();
+
     let alive = Arc::new(Mutex::new(Instant::now()));
     let alive2 = alive.clone();
     let mut session2 = session.clone();
     let nitram_for_loop = nitram.clone();
-    actix_web::rt::spawn(async move {
+    let cancel2 = cancel.clone();
+    nitram.tasks.spawn_local(async move {
+        let _cancel_on_exit = cancel2.clone().drop_guard();
         let ping_interval = Duration::from_secs(nitram_for_loop.ping_interval_in_seconds);
         let timeout = Duration::from_secs(nitram_for_loop.timeout_in_seconds);
         let mut interval = actix_web::rt::time::interval(ping_interval);
 
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = cancel2.cancelled() => break,
+                _ = interval.tick() => {}
+            }
             if session2.ping(b"").await.is_err() {
                 tracing::debug!(
                     sess = session_id.to_string(),
@@ -40,6 +277,10 @@ pub async fn handler(
                 break;
             }
 
+            // Sessions that don't poll or get requests still learn about
+            // their expiry, at least every ping interval
+            nitram_for_loop.check_expiry(&session_id).await;
+
             if Instant::now().duration_since(*alive2.lock().await) > timeout {
                 let _ = session2.close(None).await;
                 tracing::debug!(
@@ -51,63 +292,152 @@ pub async fn handler(
         }
 
         tracing::debug!(sess = session_id.to_string(), "Loop ended");
-        nitram_for_loop.remove(&session_id).await;
     });
 
-    // -- Server messages loop
-    let nitram_for_server_messages_loop = nitram.clone();
+    // -- Published server messages
+    let mut outbox = nitram.connect(&session_id).await;
     let mut session3 = session.clone();
-    actix_web::rt::spawn(async move {
-        let loop_interval = Duration::from_millis(
-            nitram_for_server_messages_loop.server_messages_interval_in_millis,
-        );
-        let mut interval = actix_web::rt::time::interval(loop_interval);
-
+    let cancel3 = cancel.clone();
+    let nitram_for_outbox = nitram.clone();
+    nitram.tasks.spawn_local(async move {
+        let _cancel_on_exit = cancel3.clone().drop_guard();
         loop {
-            interval.tick().await;
-            let server_messages = nitram_for_server_messages_loop
-                .get_server_messages_for_session(&session_id)
-                .await;
-            if !server_messages.is_empty() {
-                match serde_json::to_string(&server_messages) {
-                    Ok(json) => {
-                        let _ = session3.text(json).await;
-                    }
-                    _ => {}
-                }
+            let server_message = tokio::select! {
+                _ = cancel3.cancelled() => break,
+                server_message = outbox.recv() => server_message,
+            };
+            let Some(server_message) = server_message else {
+                break;
+            };
+            let json = server_message.to_json(nitram_for_outbox.protocol_version(&session_id));
+            if send_value(&mut session3, json, encoding).await.is_err() {
+                break;
             }
         }
     });
 
-    actix_web::rt::spawn(async move {
-        while let Some(Ok(msg)) = stream.recv().await {
-            match msg {
+    // -- Server messages loop (fallback for topics that are not published)
+    if nitram.server_messages_polling {
+        let nitram_for_server_messages_loop = nitram.clone();
+        let mut session4 = session.clone();
+        let cancel4 = cancel.clone();
+        nitram.tasks.spawn_local(async move {
+            let _cancel_on_exit = cancel4.clone().drop_guard();
+            let loop_interval = Duration::from_millis(
+                nitram_for_server_messages_loop.server_messages_interval_in_millis,
+            );
+            let mut interval = actix_web::rt::time::interval(loop_interval);
+
+            loop {
+                tokio::select! {
+                    _ = cancel4.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                let server_messages = nitram_for_server_messages_loop
+                    .get_server_messages_for_session(&session_id)
+                    .await;
+                if !server_messages.is_empty() {
+                    let json = NitramServerMessage::list_to_json(
+                        &server_messages,
+                        nitram_for_server_messages_loop.protocol_version(&session_id),
+                    );
+                    if send_value(&mut session4, json, encoding).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // -- Requests are handled concurrently, up to `max_concurrent_requests`.
+    // Frames over the limit wait in `pending`, and the socket is still read
+    // meanwhile so pongs keep the connection alive and cancels get through,
+    // until `max_pending_requests` frames wait.
+    let requests = Arc::new(Semaphore::new(nitram.max_concurrent_requests));
+    let cancels = Arc::new(Semaphore::new(MAX_CONCURRENT_CANCELS));
+    let mut pending = VecDeque::new();
+    let tasks = nitram.tasks.clone();
+    tasks.spawn_local(async move {
+        let mut close_reason = None;
+        loop {
+            let msg = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => break,
+                permit = requests.clone().acquire_owned(), if !pending.is_empty() => {
+                    let (Ok(permit), Some(frame)) = (permit, pending.pop_front()) else {
+                        break;
+                    };
+                    dispatch(&nitram, &session, &cancel, frame, session_id, permit);
+                    continue;
+                }
+                msg = stream.recv(), if pending.len() < nitram.max_pending_requests => msg,
+            };
+            let Some(Ok(msg)) = msg else {
+                break;
+            };
+            let frame = match msg {
                 AggregatedMessage::Ping(bytes) => {
-                    if session.pong(&bytes).await.is_err() {
-                        return;
+                    let pong = session.pong(&bytes).await;
+                    if pong.is_err() {
+                        break;
                     }
+                    continue;
                 }
 
                 AggregatedMessage::Text(string) => {
                     tracing::debug!(sess = session_id.to_string(), "Relaying text: {}", string);
-                    let res = nitram.send(string, &session_id).await;
-                    let _ = session.text(res).await;
+                    Frame::text(&string)
+                }
+
+                AggregatedMessage::Binary(bytes) => {
+                    Frame::binary(&bytes, nitram.encoding(&session_id))
                 }
 
                 AggregatedMessage::Close(reason) => {
-                    let _ = session.close(reason).await;
                     tracing::debug!(sess = session_id.to_string(), "Got close, bailing");
-                    return;
+                    close_reason = reason;
+                    break;
                 }
 
                 AggregatedMessage::Pong(_) => {
                     *alive.lock().await = Instant::now();
+                    continue;
                 }
-
-                _ => (),
             };
+            // A request cancelled before it got a permit is answered right
+            // away, and so is the cancel
+            let cancelled = take_cancelled(&mut pending, &frame);
+            if !cancelled.is_empty() {
+                for cancelled in cancelled {
+                    answer(
+                        &nitram,
+                        &mut session,
+                        cancelled,
+                        &session_id,
+                        Err(Error::Cancelled),
+                    )
+                    .await;
+                }
+                answer(&nitram, &mut session, frame, &session_id, Ok(json!(true))).await;
+                continue;
+            }
+            let cancel_permit = match frame.is_cancel() {
+                true => cancels.clone().try_acquire_owned().ok(),
+                false => None,
+            };
+            match cancel_permit {
+                Some(permit) => dispatch(&nitram, &session, &cancel, frame, session_id, permit),
+                None => pending.push_back(frame),
+            }
+        }
+        cancel.cancel();
+        if nitram.shutdown_token.is_cancelled() {
+            close_reason = Some(CloseReason {
+                code: CloseCode::Restart,
+                description: Some("nitram_shutdown".to_string()),
+            });
         }
-        let _ = session.close(None).await;
+        let _ = session.close(close_reason).await;
         nitram.remove(&session_id).await;
     });
 
@@ -0,0 +1,247 @@
+#![cfg(feature = "jwt")]
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chrono::{Duration, Utc};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rsa::{
+        pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey, LineEnding},
+        RsaPrivateKey,
+    };
+    use serde_json::{json, Value};
+    use std::sync::OnceLock;
+    use tracing_test::traced_test;
+
+    use nitram::{
+        auth::{Authenticator, TokenSource},
+        jwt::{Algorithm, DecodingKey, JwtAuth},
+        Nitram, NitramBuilder,
+    };
+
+    const SECRET: &[u8] = b"a secret only the tests know";
+
+    fn in_an_hour() -> i64 {
+        (Utc::now() + Duration::hours(1)).timestamp()
+    }
+
+    fn hs256(claims: Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    /// Generating a key is slow, the tests share one.
+    fn rsa_key() -> &'static RsaPrivateKey {
+        static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+        KEY.get_or_init(|| RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048).unwrap())
+    }
+
+    fn rs256(claims: Value) -> String {
+        let der = rsa_key().to_pkcs1_der().unwrap();
+        encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &EncodingKey::from_rsa_der(der.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn rsa_public_pem() -> String {
+        rsa_key()
+            .to_public_key()
+            .to_pkcs1_pem(LineEnding::LF)
+            .unwrap()
+    }
+
+    async fn send_authenticate(nitram: &Nitram, token: &str) -> Value {
+        let ws_session_id = nitram.insert().await;
+        let req = json!({
+            "id": "1",
+            "method": "Authenticate",
+            "params": { "token": token },
+        });
+        let response = nitram.send(req.to_string(), &ws_session_id).await;
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_hs256() {
+        let jwt = JwtAuth::from_secret(SECRET);
+        let exp = in_an_hour();
+        let user = jwt.decode(&hs256(json!({ "sub": "alice", "exp": exp })));
+        let user = user.unwrap();
+        assert_eq!(user.user_id, "alice");
+        assert_eq!(user.expires_at.timestamp(), exp);
+
+        let other = JwtAuth::from_secret(b"another secret");
+        assert!(other
+            .decode(&hs256(json!({ "sub": "alice", "exp": exp })))
+            .is_err());
+    }
+
+    #[test]
+    fn test_rs256() {
+        let token = rs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+        let jwt = JwtAuth::from_rsa_pem(rsa_public_pem().as_bytes()).unwrap();
+        assert_eq!(jwt.decode(&token).unwrap().user_id, "alice");
+
+        let der = rsa_key().to_public_key().to_pkcs1_der().unwrap();
+        let jwt = JwtAuth::new(DecodingKey::from_rsa_der(der.as_bytes()), Algorithm::RS256);
+        assert_eq!(jwt.decode(&token).unwrap().user_id, "alice");
+
+        // A token signed with the secret is not accepted for an RSA key
+        let hs_token = hs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+        assert!(jwt.decode(&hs_token).is_err());
+        assert!(JwtAuth::from_rsa_pem(b"not a key").is_err());
+    }
+
+    #[test]
+    fn test_issuer_and_audience() {
+        let jwt = JwtAuth::from_secret(SECRET)
+            .set_issuer("https://auth.example.com")
+            .set_audience("chat");
+        let exp = in_an_hour();
+        let token = |claims: Value| hs256(claims);
+
+        let valid = token(json!({
+            "sub": "alice", "exp": exp, "iss": "https://auth.example.com", "aud": "chat"
+        }));
+        assert!(jwt.decode(&valid).is_ok());
+
+        let wrong_issuer = token(json!({
+            "sub": "alice", "exp": exp, "iss": "https://evil.example.com", "aud": "chat"
+        }));
+        assert!(jwt.decode(&wrong_issuer).is_err());
+
+        let wrong_audience = token(json!({
+            "sub": "alice", "exp": exp, "iss": "https://auth.example.com", "aud": "admin"
+        }));
+        assert!(jwt.decode(&wrong_audience).is_err());
+
+        let missing = token(json!({ "sub": "alice", "exp": exp }));
+        assert!(jwt.decode(&missing).is_err());
+    }
+
+    #[test]
+    fn test_claim_mapping() {
+        let jwt = JwtAuth::from_secret(SECRET)
+            .set_user_id_claim("uid")
+            .set_expires_at_claim("session_end");
+        let session_end = in_an_hour();
+        let token = hs256(json!({ "uid": 42, "session_end": session_end }));
+        let user = jwt.decode(&token).unwrap();
+        assert_eq!(user.user_id, "42");
+        assert_eq!(user.expires_at.timestamp(), session_end);
+
+        // The mapped claims are required
+        let token = hs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+        assert!(jwt.decode(&token).is_err());
+        let token = hs256(json!({ "uid": "alice" }));
+        assert!(jwt.decode(&token).is_err());
+    }
+
+    #[test]
+    fn test_leeway() {
+        let expired = (Utc::now() - Duration::seconds(30)).timestamp();
+        let token = hs256(json!({ "sub": "alice", "exp": expired }));
+        assert!(JwtAuth::from_secret(SECRET).decode(&token).is_ok());
+        assert!(JwtAuth::from_secret(SECRET)
+            .set_leeway(0)
+            .decode(&token)
+            .is_err());
+
+        // Also for a mapped expiry claim
+        let token = hs256(json!({ "sub": "alice", "valid_until": expired }));
+        let jwt = JwtAuth::from_secret(SECRET).set_expires_at_claim("valid_until");
+        assert!(jwt.clone().set_leeway(60).decode(&token).is_ok());
+        assert!(jwt.set_leeway(10).decode(&token).is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_authenticate_handler() {
+        let nitram = NitramBuilder::default()
+            .set_jwt(JwtAuth::from_secret(SECRET))
+            .build();
+
+        let token = hs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+        let response = send_authenticate(&nitram, &token).await;
+        assert_eq!(response["ok"], json!(true));
+        assert_eq!(response["response"], json!("alice"));
+
+        let expired = (Utc::now() - Duration::hours(1)).timestamp();
+        let token = hs256(json!({ "sub": "alice", "exp": expired }));
+        let response = send_authenticate(&nitram, &token).await;
+        assert_eq!(response["ok"], json!(false));
+
+        let response = send_authenticate(&nitram, "not a token").await;
+        assert_eq!(response["ok"], json!(false));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_refresh_handler() {
+        let nitram = NitramBuilder::default()
+            .set_jwt(JwtAuth::from_secret(SECRET))
+            .build();
+        let ws_session_id = nitram.insert().await;
+        let send = |method: &str, token: String| {
+            let req = json!({ "id": "1", "method": method, "params": { "token": token } });
+            let nitram = nitram.clone();
+            async move {
+                let response = nitram.send(req.to_string(), &ws_session_id).await;
+                serde_json::from_str::<Value>(&response).unwrap()
+            }
+        };
+
+        let token = hs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+        let response = send("Refresh", token.clone()).await;
+        assert_eq!(response["ok"], json!(false));
+        send("Authenticate", token).await;
+
+        let later = (Utc::now() + Duration::hours(2)).timestamp();
+        let token = hs256(json!({ "sub": "alice", "exp": later }));
+        let response = send("Refresh", token).await;
+        assert_eq!(response["ok"], json!(true));
+        assert_eq!(response["response"], json!("alice"));
+
+        // A token of another user doesn't take over the session
+        let token = hs256(json!({ "sub": "bob", "exp": later }));
+        let response = send("Refresh", token).await;
+        assert_eq!(response["ok"], json!(false));
+        let hello = nitram.hello(&ws_session_id).await;
+        assert_eq!(hello.user_id.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_handshake() {
+        let jwt = JwtAuth::from_secret(SECRET);
+        let token = hs256(json!({ "sub": "alice", "exp": in_an_hour() }));
+
+        let req = TestRequest::default()
+            .insert_header(("authorization", format!("Bearer {token}")))
+            .to_http_request();
+        let user = jwt.authenticate(&req).await.unwrap();
+        assert_eq!(user.user_id, "alice");
+
+        let req = TestRequest::default()
+            .uri(&format!("/ws?nitram_token={token}"))
+            .to_http_request();
+        assert!(jwt.authenticate(&req).await.is_some());
+        let header_only = jwt.set_token_sources(&[TokenSource::Header("authorization")]);
+        assert!(header_only.authenticate(&req).await.is_none());
+
+        let nitram = NitramBuilder::default()
+            .set_jwt(JwtAuth::from_secret(SECRET))
+            .build();
+        let ws_session_id = nitram.insert().await;
+        assert!(nitram.authenticate(&ws_session_id, &req).await);
+        let hello = nitram.hello(&ws_session_id).await;
+        assert_eq!(hello.user_id.as_deref(), Some("alice"));
+    }
+}
@@ -1,16 +1,25 @@
 #[cfg(test)]
 mod tests {
-    use chrono::Utc;
+    use chrono::{Duration, Utc};
+    use futures_util::stream;
     use serde::{Deserialize, Serialize};
     use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
     use tracing_test::traced_test;
     use uuid::Uuid;
 
     use nitram::{
-        auth::{WSSessionAnonymResource, WSSessionAuthedResource},
-        error::MethodError,
-        models::UserSession,
-        FromResources, IntoParams, Nitram, NitramBuilder,
+        auth::{NitramSession, WSSessionAnonymResource, WSSessionAuthedResource},
+        backend::{FileBackend, MemoryBackend, SessionBackend},
+        encoding::Encoding,
+        error::{AppError, MethodError},
+        hooks::HookContext,
+        models::{Store, UserSession},
+        params::{self, rules, ParamError, Validate},
+        stream::NitramStream,
+        BatchExecution, FromResources, IntoParams, Nitram, NitramBuilder, ProtocolVersion,
+        RequestCancellation,
     };
 
     #[derive(Clone)]
@@ -31,6 +40,14 @@ mod tests {
         Ok(params.code)
     }
 
+    async fn mock_refresh_handler(
+        session: WSSessionAnonymResource,
+        params: MockParams,
+    ) -> Result<bool, MethodError> {
+        let expires_at = Utc::now() + Duration::hours(2);
+        Ok(session.refresh(&params.code, expires_at).await)
+    }
+
     async fn mock_private_handler(
         _mm: ModelManager,
         _session: WSSessionAuthedResource,
@@ -42,6 +59,97 @@ mod tests {
         Ok(params.code.to_uppercase())
     }
 
+    async fn mock_count_handler(
+        _session: WSSessionAuthedResource,
+        mut store: Store,
+        _params: MockParams,
+    ) -> Result<i32, MethodError> {
+        let count = store.get::<i32>("count").await.unwrap_or_default() + 1;
+        store.insert("count", json!(count)).await;
+        Ok(count)
+    }
+
+    /// Streams the letters of the code, a `!` ends the stream with an error
+    async fn mock_stream_handler(
+        _mm: ModelManager,
+        _session: WSSessionAnonymResource,
+        params: MockParams,
+    ) -> Result<NitramStream<String>, MethodError> {
+        let letters = params.code.chars().map(|letter| match letter {
+            '!' => Err(MethodError::Server),
+            letter => Ok(letter.to_string()),
+        });
+        Ok(Box::pin(stream::iter(letters.collect::<Vec<_>>())))
+    }
+
+    async fn mock_private_stream_handler(
+        _session: WSSessionAuthedResource,
+        params: MockParams,
+    ) -> Result<NitramStream<String>, MethodError> {
+        Ok(Box::pin(stream::iter([Ok(params.code.to_uppercase())])))
+    }
+
+    /// Waits until the request is cancelled
+    async fn mock_wait_handler(
+        cancellation: RequestCancellation,
+        _params: MockParams,
+    ) -> Result<bool, MethodError> {
+        cancellation.cancelled().await;
+        Ok(cancellation.is_cancelled())
+    }
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "code", content = "data", rename_all = "snake_case")]
+    pub enum MockAppError {
+        NameTaken { name: String },
+    }
+    impl AppError for MockAppError {}
+    impl std::fmt::Display for MockAppError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                MockAppError::NameTaken { .. } => write!(f, "name taken"),
+            }
+        }
+    }
+
+    async fn mock_app_error_handler(
+        _session: WSSessionAnonymResource,
+        params: MockParams,
+    ) -> Result<String, MethodError> {
+        Err(MockAppError::NameTaken { name: params.code })?
+    }
+
+    #[derive(Deserialize)]
+    pub struct MockValidatedParams {
+        channel: String,
+        limit: u32,
+    }
+    impl IntoParams for MockValidatedParams {
+        fn into_params(value: Option<params::Value>) -> params::Result<Self> {
+            params::parse(value)
+        }
+    }
+    impl Validate for MockValidatedParams {
+        fn validate(&self) -> Vec<ParamError> {
+            let checks = [
+                ("params.channel", rules::length(&self.channel, 1..=5)),
+                ("params.channel", rules::regex(&self.channel, "^[a-z]+$")),
+                ("params.limit", rules::range(&self.limit, 1..=10)),
+            ];
+            checks
+                .into_iter()
+                .filter_map(|(path, check)| check.err().map(|e| ParamError::new(path, e)))
+                .collect()
+        }
+    }
+
+    async fn mock_validated_handler(
+        _session: WSSessionAnonymResource,
+        params: MockValidatedParams,
+    ) -> Result<u32, MethodError> {
+        Ok(params.limit)
+    }
+
     // nitram_api!(MockAPI, MockParams, String);
     // nitram_api!(MockPrivateAPI, MockParams, String);
 
@@ -56,7 +164,10 @@ mod tests {
         let cb = NitramBuilder::default()
             .add_resource(mm)
             .add_public_handler("Mock", mock_handler)
-            .add_private_handler("MockPrivate", mock_private_handler);
+            .add_private_handler("MockPrivate", mock_private_handler)
+            .add_public_handler("MockWait", mock_wait_handler)
+            .add_public_stream_handler("MockStream", mock_stream_handler)
+            .add_private_stream_handler("MockPrivateStream", mock_private_stream_handler);
         let nitram = cb.build();
 
         let anonym = nitram.insert().await;
@@ -64,7 +175,7 @@ mod tests {
         let db_session = UserSession {
             id: Uuid::new_v4(),
             user_id: "fake_user".to_string(),
-            expires_at: Utc::now(),
+            expires_at: Utc::now() + Duration::hours(1),
         };
         nitram._auth_ws_session(authed, db_session).await;
         Context {
@@ -74,6 +185,28 @@ mod tests {
         }
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_new() {
+        let nitram = Nitram::new(
+            rpc_router::Router::builder().build(),
+            rpc_router::Router::builder().build(),
+            rpc_router::Router::builder().build(),
+            vec![],
+            vec![],
+            vec![],
+            Some(10),
+            None,
+            None,
+            Some(1024),
+        );
+        assert_eq!(nitram.ping_interval_in_seconds, 10);
+        assert_eq!(nitram.server_messages_interval_in_millis, 1000);
+        assert_eq!(nitram.timeout_in_seconds, 90);
+        assert_eq!(nitram.max_frame_size, 1024);
+        assert_eq!(nitram.max_concurrent_requests, 16);
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_send() -> Result<(), MethodError> {
@@ -146,6 +279,51 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_protocol_v2() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        ctx.nitram
+            .set_protocol_version(&ctx.anonym_ws_sess_id, ProtocolVersion::V2);
+        let req = json!({
+            "id": "1",
+            "method": "MockPrivate",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "1",
+            "method": "MockPrivate",
+            "response": {
+                "code": "not_authorized",
+                "message": "not authorized"
+            },
+            "ok": false
+        });
+        let response = ctx
+            .nitram
+            .send(req.to_string(), &ctx.anonym_ws_sess_id)
+            .await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        // Handler errors
+        ctx.nitram
+            .set_protocol_version(&ctx.ws_sess_id, ProtocolVersion::V2);
+        let req = json!({
+            "id": "2",
+            "method": "MockPrivate",
+            "params": {
+                "code": "return error"
+            },
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"]["code"], "server_error");
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_send_error() -> Result<(), MethodError> {
@@ -169,6 +347,41 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_app_error() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .add_public_handler("MockAppError", mock_app_error_handler)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let req = json!({
+            "id": "1",
+            "method": "MockAppError",
+            "params": {
+                "code": "martin"
+            },
+        });
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed["response"],
+            "(~ name taken ~~ {\"name\":\"martin\"} ~)"
+        );
+
+        nitram.set_protocol_version(&ws_sess_id, ProtocolVersion::V2);
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed["response"],
+            json!({
+                "code": "name_taken",
+                "message": "name taken",
+                "data": { "name": "martin" }
+            })
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_send_wrong_params() -> Result<(), MethodError> {
@@ -191,4 +404,1249 @@ mod tests {
         assert_eq!(parsed, res);
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_invalid_params() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .add_public_handler("MockValidated", mock_validated_handler)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        nitram.set_protocol_version(&ws_sess_id, ProtocolVersion::V2);
+        let send = |params: serde_json::Value| {
+            let nitram = nitram.clone();
+            async move {
+                let req = json!({ "id": "1", "method": "MockValidated", "params": params });
+                let response = nitram.send(req.to_string(), &ws_sess_id).await;
+                serde_json::from_str::<serde_json::Value>(&response).unwrap()["response"].clone()
+            }
+        };
+
+        // Every violation is reported
+        let response = send(json!({ "channel": "General", "limit": 0 })).await;
+        assert_eq!(response["code"], "bad_request");
+        assert_eq!(
+            response["data"],
+            json!([
+                { "path": "params.channel", "message": "length must be in 1..=5" },
+                { "path": "params.channel", "message": "must match ^[a-z]+$" },
+                { "path": "params.limit", "message": "must be in 1..=10" },
+            ])
+        );
+
+        // Deserialization errors point to the field
+        let response = send(json!({ "limit": 1 })).await;
+        assert_eq!(
+            response["data"],
+            json!([{ "path": "params.channel", "message": "missing field" }])
+        );
+        let response = send(json!({ "channel": "dev", "limit": "one" })).await;
+        assert_eq!(response["data"][0]["path"], "params.limit");
+
+        let response = send(json!({ "channel": "dev", "limit": 3 })).await;
+        assert_eq!(response, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_publish() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let mut outbox = ctx.nitram.connect(&ctx.ws_sess_id).await;
+        let mut anonym_outbox = ctx.nitram.connect(&ctx.anonym_ws_sess_id).await;
+        let req = json!({
+            "id": "1",
+            "method": "nitram_topic_register",
+            "params": {
+                "topic": "Messages",
+                "handler_params": {}
+            },
+        });
+        ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+
+        let delivered = ctx.nitram.publish("Messages", json!("hello")).await;
+        assert_eq!(delivered, 1);
+        let server_message = outbox.recv().await.unwrap();
+        assert_eq!(server_message.topic, "Messages");
+        assert_eq!(server_message.payload, json!("hello"));
+        assert!(anonym_outbox.try_recv().is_err());
+
+        let delivered = ctx.nitram.publish("Other", json!("hello")).await;
+        assert_eq!(delivered, 0);
+        assert!(outbox.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_invalid_json() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        // Truncated frame, the id and method are still echoed
+        let req = r#"{"id": "1", "method": "Mock", "params": {"code": "#;
+        let res = json!({
+            "id": "1",
+            "method": "Mock",
+            "response": "(~ invalid json ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req, &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        let response = ctx.nitram.send("not json", &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["id"], "_err");
+        assert_eq!(parsed["method"], "_err");
+        assert_eq!(parsed["response"], "(~ invalid json ~)");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_missing_id() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "method": "Mock",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "_err",
+            "method": "Mock",
+            "response": "(~ missing id ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_missing_method() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        ctx.nitram
+            .set_protocol_version(&ctx.ws_sess_id, ProtocolVersion::V2);
+        let req = json!({
+            "id": "1",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "1",
+            "method": "_err",
+            "response": {
+                "code": "missing_method",
+                "message": "missing method"
+            },
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_params_not_object() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "id": "1",
+            "method": "Mock",
+            "params": ["hello"],
+        });
+        let res = json!({
+            "id": "1",
+            "method": "Mock",
+            "response": "(~ params must be an object ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        Ok(())
+    }
+
+    /// Sends a request to a stream handler, returns the partial responses
+    /// and the response
+    async fn send_streaming(
+        nitram: &Nitram,
+        ws_sess_id: &Uuid,
+        req: serde_json::Value,
+    ) -> (Vec<serde_json::Value>, serde_json::Value) {
+        let (partials, mut receiver) = mpsc::unbounded_channel();
+        let response = nitram
+            .send_streaming(req.to_string(), ws_sess_id, &partials)
+            .await;
+        drop(partials);
+        let mut received = vec![];
+        while let Some(partial) = receiver.recv().await {
+            received.push(partial);
+        }
+        (received, serde_json::from_str(&response).unwrap())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_stream() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({ "id": "1", "method": "MockStream", "params": { "code": "abc" } });
+        let (partials, response) = send_streaming(&ctx.nitram, &ctx.anonym_ws_sess_id, req).await;
+        let expected: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|letter| json!({ "id": "1", "method": "MockStream", "partial": letter }))
+            .collect();
+        assert_eq!(partials, expected);
+        assert_eq!(
+            response,
+            json!({ "id": "1", "method": "MockStream", "response": 3, "ok": true })
+        );
+
+        // `send` drops the items
+        let req = json!({ "id": "2", "method": "MockPrivateStream", "params": { "code": "abc" } });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "id": "2", "method": "MockPrivateStream", "response": 1, "ok": true })
+        );
+
+        let (partials, response) = send_streaming(&ctx.nitram, &ctx.anonym_ws_sess_id, req).await;
+        assert!(partials.is_empty());
+        assert_eq!(response["response"], json!("(~ not authorized ~)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_stream_error() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({ "id": "1", "method": "MockStream", "params": { "code": "ab!c" } });
+        let (partials, response) = send_streaming(&ctx.nitram, &ctx.anonym_ws_sess_id, req).await;
+        assert_eq!(partials.len(), 2);
+        assert_eq!(
+            response,
+            json!({ "id": "1", "method": "MockStream", "response": "(~ server error ~)", "ok": false })
+        );
+
+        ctx.nitram
+            .set_protocol_version(&ctx.anonym_ws_sess_id, ProtocolVersion::JsonRpc);
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "MockStream",
+            "params": { "code": "a!" },
+        });
+        let (partials, response) = send_streaming(&ctx.nitram, &ctx.anonym_ws_sess_id, req).await;
+        assert_eq!(
+            partials,
+            vec![json!({
+                "jsonrpc": "2.0",
+                "method": "nitram_partial",
+                "params": { "id": 7, "partial": "a" },
+            })]
+        );
+        assert_eq!(response["error"]["code"], json!(-32000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cancel() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let wait = json!({ "id": "1", "method": "MockWait", "params": { "code": "a" } });
+        let cancel = |id: &str, target: &str| {
+            json!({ "id": id, "method": "nitram_cancel", "params": { "id": target } }).to_string()
+        };
+        let (response, cancelled) = tokio::join!(
+            ctx.nitram.send(wait.to_string(), &ctx.anonym_ws_sess_id),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                ctx.nitram
+                    .send(cancel("2", "1"), &ctx.anonym_ws_sess_id)
+                    .await
+            },
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "id": "1", "method": "MockWait", "response": "(~ cancelled ~)", "ok": false })
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&cancelled).unwrap();
+        assert_eq!(parsed["response"], json!(true));
+
+        // Not in flight anymore
+        let response = ctx
+            .nitram
+            .send(cancel("3", "1"), &ctx.anonym_ws_sess_id)
+            .await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!(false));
+
+        // Only the requests of the same ws session
+        let (response, cancelled) = tokio::join!(
+            tokio::time::timeout(
+                std::time::Duration::from_millis(200),
+                ctx.nitram.send(wait.to_string(), &ctx.anonym_ws_sess_id),
+            ),
+            ctx.nitram.send(cancel("4", "1"), &ctx.ws_sess_id),
+        );
+        assert!(response.is_err());
+        let parsed = serde_json::from_str::<serde_json::Value>(&cancelled).unwrap();
+        assert_eq!(parsed["response"], json!(false));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cancel_on_disconnect() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        ctx.nitram
+            .set_protocol_version(&ctx.anonym_ws_sess_id, ProtocolVersion::JsonRpc);
+        let wait = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "MockWait",
+            "params": { "code": "a" },
+        });
+        let (response, _) = tokio::join!(
+            ctx.nitram.send(wait.to_string(), &ctx.anonym_ws_sess_id),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                ctx.nitram.remove(&ctx.anonym_ws_sess_id).await
+            },
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["id"], json!(1));
+        assert_eq!(parsed["error"]["code"], json!(-32800));
+        assert_eq!(parsed["error"]["data"]["code"], json!("cancelled"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_cancel_typed_ids() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        ctx.nitram
+            .set_protocol_version(&ctx.anonym_ws_sess_id, ProtocolVersion::JsonRpc);
+        let wait = |id: serde_json::Value| {
+            json!({ "jsonrpc": "2.0", "id": id, "method": "MockWait", "params": { "code": "a" } })
+                .to_string()
+        };
+        let (number, string, cancelled) = tokio::join!(
+            ctx.nitram.send(wait(json!(1)), &ctx.anonym_ws_sess_id),
+            ctx.nitram.send(wait(json!("1")), &ctx.anonym_ws_sess_id),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                let cancel = json!({
+                    "jsonrpc": "2.0",
+                    "id": 2,
+                    "method": "nitram_cancel",
+                    "params": { "id": 1 },
+                });
+                let cancelled = ctx
+                    .nitram
+                    .send(cancel.to_string(), &ctx.anonym_ws_sess_id)
+                    .await;
+                // `"1"` is still in flight
+                assert!(ctx.nitram.cancel(&ctx.anonym_ws_sess_id, &json!("1")));
+                cancelled
+            },
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&cancelled).unwrap();
+        assert_eq!(parsed["result"], json!(true));
+        for (response, id) in [(number, json!(1)), (string, json!("1"))] {
+            let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+            assert_eq!(parsed["id"], id);
+            assert_eq!(parsed["error"]["code"], json!(-32800));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_duplicate_id() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let wait = json!({ "id": "1", "method": "MockWait", "params": { "code": "a" } });
+        let (response, duplicate) = tokio::join!(
+            ctx.nitram.send(wait.to_string(), &ctx.anonym_ws_sess_id),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                let duplicate = ctx
+                    .nitram
+                    .send(wait.to_string(), &ctx.anonym_ws_sess_id)
+                    .await;
+                // The first request is still tracked
+                assert!(ctx.nitram.cancel(&ctx.anonym_ws_sess_id, &json!("1")));
+                duplicate
+            },
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&duplicate).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "id": "1", "method": "MockWait", "response": "(~ duplicate id ~)", "ok": false })
+        );
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!("(~ cancelled ~)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_request_timeout() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .set_request_timeout(50)
+            .set_method_timeout("MockWaitLonger", 0)
+            .add_public_handler("MockWait", mock_wait_handler)
+            .add_public_handler("MockWaitLonger", mock_wait_handler)
+            .add_public_handler("Mock", mock_handler)
+            .add_resource(ModelManager {})
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let req = json!({ "id": "1", "method": "MockWait", "params": { "code": "" } });
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "id": "1", "method": "MockWait", "response": "(~ timeout ~)", "ok": false })
+        );
+
+        // No timeout for the method
+        let req = json!({ "id": "2", "method": "MockWaitLonger", "params": { "code": "" } });
+        let response = tokio::time::timeout(
+            std::time::Duration::from_millis(200),
+            nitram.send(req.to_string(), &ws_sess_id),
+        )
+        .await;
+        assert!(response.is_err());
+
+        // Fast requests are not affected
+        let req = json!({ "id": "3", "method": "Mock", "params": { "code": "a" } });
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["ok"], json!(true));
+
+        nitram.set_protocol_version(&ws_sess_id, ProtocolVersion::JsonRpc);
+        let req =
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "MockWait", "params": { "code": "" } });
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["error"]["code"], json!(-32001));
+        assert_eq!(parsed["error"]["data"]["code"], json!("timeout"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_request_deadline() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let deadline = Utc::now() + Duration::milliseconds(50);
+        let req = json!({
+            "id": "1",
+            "method": "MockWait",
+            "params": { "code": "" },
+            "deadline": deadline,
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!("(~ timeout ~)"));
+
+        // Already past
+        let req = json!({
+            "id": "2",
+            "method": "Mock",
+            "params": { "code": "a" },
+            "deadline": Utc::now() - Duration::seconds(1),
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!("(~ timeout ~)"));
+
+        let req = json!({
+            "id": "3",
+            "method": "Mock",
+            "params": { "code": "a" },
+            "deadline": "tomorrow",
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "id": "3", "method": "Mock", "response": "(~ invalid deadline ~)", "ok": false })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_batch() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!([
+            { "id": "1", "method": "Mock", "params": { "code": "a" } },
+            { "id": "2", "method": "MockPrivate", "params": { "code": "b" } },
+            { "id": "3", "params": { "code": "c" } },
+        ]);
+        let res = json!([
+            { "id": "1", "method": "Mock", "response": "a", "ok": true },
+            { "id": "2", "method": "MockPrivate", "response": "B", "ok": true },
+            { "id": "3", "method": "_err", "response": "(~ missing method ~)", "ok": false },
+        ]);
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        let response = ctx.nitram.send("[]", &ctx.ws_sess_id).await;
+        assert_eq!(response, "[]");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_batch_sequential() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .set_batch_execution(BatchExecution::Sequential)
+            .add_private_handler("MockCount", mock_count_handler)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let db_session = UserSession {
+            id: ws_sess_id,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        nitram._auth_ws_session(ws_sess_id, db_session).await;
+        let req: Vec<_> = (1..=3)
+            .map(|id| json!({ "id": id.to_string(), "method": "MockCount", "params": { "code": "" } }))
+            .collect();
+        let response = nitram.send(json!(req).to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        let counts: Vec<_> = parsed
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|res| res["response"].clone())
+            .collect();
+        assert_eq!(counts, vec![json!(1), json!(2), json!(3)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_batch_too_large() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .set_max_batch_size(2)
+            .add_resource(ModelManager {})
+            .add_public_handler("Mock", mock_handler)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let req = json!([
+            { "id": "1", "method": "Mock", "params": { "code": "a" } },
+            { "id": "2", "method": "Mock", "params": { "code": "b" } },
+            { "id": "3", "method": "Mock", "params": { "code": "c" } },
+        ]);
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        for (i, res) in parsed.as_array().unwrap().iter().enumerate() {
+            let expected = json!({
+                "id": (i + 1).to_string(),
+                "method": "Mock",
+                "response": "(~ batch too large ~)",
+                "ok": false
+            });
+            assert_eq!(res, &expected);
+        }
+        assert_eq!(parsed.as_array().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_encoded() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "id": "1",
+            "method": "Mock",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "1",
+            "method": "Mock",
+            "response": "hello",
+            "ok": true
+        });
+
+        ctx.nitram
+            .set_encoding(&ctx.ws_sess_id, Encoding::MessagePack);
+        let payload = rmp_serde::to_vec_named(&req).unwrap();
+        let response = ctx.nitram.send_encoded(&payload, &ctx.ws_sess_id).await;
+        let parsed = rmp_serde::from_slice::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        ctx.nitram.set_encoding(&ctx.ws_sess_id, Encoding::Cbor);
+        let mut payload = vec![];
+        ciborium::into_writer(&req, &mut payload).unwrap();
+        let response = ctx.nitram.send_encoded(&payload, &ctx.ws_sess_id).await;
+        let parsed = ciborium::from_reader::<serde_json::Value, _>(&response[..]).unwrap();
+        assert_eq!(parsed, res);
+
+        // Frames that can't be decoded
+        let response = ctx.nitram.send_encoded(&[0xff], &ctx.ws_sess_id).await;
+        let parsed = ciborium::from_reader::<serde_json::Value, _>(&response[..]).unwrap();
+        assert_eq!(parsed["response"], "(~ invalid json ~)");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_upgrade_required() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .set_min_protocol_version(ProtocolVersion::V2)
+            .add_resource(ModelManager {})
+            .add_public_handler("Mock", mock_handler)
+            .build();
+        let req = json!({
+            "id": "1",
+            "method": "Mock",
+            "params": {
+                "code": "hello"
+            },
+        });
+
+        // Too old: clients that don't ask for a version speak version 1
+        let ws_sess_id = nitram.insert().await;
+        assert!(!nitram.negotiate_protocol_version(&ws_sess_id, None));
+        let res = json!({
+            "id": "1",
+            "method": "Mock",
+            "response": "(~ upgrade required ~~ {\"requested\":\"1\",\"supported\":[\"2\",\"jsonrpc\"]} ~)",
+            "ok": false
+        });
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        // Too new
+        let ws_sess_id = nitram.insert().await;
+        assert!(!nitram.negotiate_protocol_version(&ws_sess_id, Some("3")));
+        let response = nitram.send(json!([req]).to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed[0]["id"], "1");
+        assert_eq!(parsed[0]["ok"], false);
+        let upgrade_required = nitram.upgrade_required(&ws_sess_id).unwrap();
+        assert_eq!(upgrade_required["requested"], "3");
+
+        // Supported versions are served side by side
+        for version in ["2", "jsonrpc"] {
+            let ws_sess_id = nitram.insert().await;
+            assert!(nitram.negotiate_protocol_version(&ws_sess_id, Some(version)));
+            assert!(nitram.upgrade_required(&ws_sess_id).is_none());
+        }
+        let ws_sess_id = nitram.insert().await;
+        nitram.negotiate_protocol_version(&ws_sess_id, Some("2"));
+        let response = nitram.send(req.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], "hello");
+        Ok(())
+    }
+
+    async fn send_json_rpc(ctx: &Context, ws_sess_id: &Uuid, req: serde_json::Value) -> String {
+        ctx.nitram
+            .set_protocol_version(ws_sess_id, ProtocolVersion::JsonRpc);
+        ctx.nitram.send(req.to_string(), ws_sess_id).await
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_json_rpc() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "Mock",
+            "params": { "code": "hello" },
+        });
+        let res = json!({ "jsonrpc": "2.0", "id": 1, "result": "hello" });
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, req).await;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response).unwrap(),
+            res
+        );
+
+        // Params by position
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": "2",
+            "method": "MockPrivate",
+            "params": ["hello"],
+        });
+        let res = json!({ "jsonrpc": "2.0", "id": "2", "result": "HELLO" });
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, req).await;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response).unwrap(),
+            res
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_json_rpc_errors() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let response = |req| send_json_rpc(&ctx, &ctx.anonym_ws_sess_id, req);
+        let error = |response: String| {
+            serde_json::from_str::<serde_json::Value>(&response).unwrap()["error"].clone()
+        };
+
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": "Unknown" });
+        assert_eq!(error(response(req).await)["code"], -32601);
+
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": "Mock", "params": {} });
+        let res = json!({
+            "code": -32602,
+            "message": "bad request",
+            "data": {
+                "code": "bad_request",
+                "message": "bad request",
+                "data": [{ "path": "params.code", "message": "missing field" }]
+            }
+        });
+        assert_eq!(error(response(req).await), res);
+
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "MockPrivate",
+            "params": { "code": "hello" }
+        });
+        let res = json!({
+            "code": -32000,
+            "message": "not authorized",
+            "data": { "code": "not_authorized", "message": "not authorized" }
+        });
+        assert_eq!(error(response(req).await), res);
+
+        // Invalid requests
+        let req = json!({ "id": 1, "method": "Mock" });
+        let res = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32600, "message": "invalid request" }
+        });
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response(req).await).unwrap(),
+            res
+        );
+        let req = json!({ "jsonrpc": "2.0", "id": 1, "method": "Mock", "params": "hello" });
+        assert_eq!(error(response(req).await)["code"], -32600);
+
+        let response = ctx.nitram.send("{", &ctx.anonym_ws_sess_id).await;
+        let res = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": -32700, "message": "parse error" }
+        });
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response).unwrap(),
+            res
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_json_rpc_notification() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({ "jsonrpc": "2.0", "method": "nitram_logout" });
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, req).await;
+        assert_eq!(response, "");
+
+        // The notification was handled
+        let req = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "MockPrivate",
+            "params": { "code": "hello" }
+        });
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, req).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["error"]["data"]["code"], "not_authorized");
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_json_rpc_batch() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "Mock", "params": { "code": "a" } },
+            { "jsonrpc": "2.0", "method": "Mock", "params": { "code": "b" } },
+            { "jsonrpc": "2.0", "id": 3, "method": "MockPrivate", "params": { "code": "c" } },
+            1,
+        ]);
+        let res = json!([
+            { "jsonrpc": "2.0", "id": 1, "result": "a" },
+            { "jsonrpc": "2.0", "id": 3, "result": "C" },
+            {
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32600, "message": "invalid request" }
+            },
+        ]);
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, req).await;
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&response).unwrap(),
+            res
+        );
+
+        // Only notifications
+        let req = json!([{ "jsonrpc": "2.0", "method": "Mock", "params": { "code": "a" } }]);
+        assert_eq!(send_json_rpc(&ctx, &ctx.ws_sess_id, req).await, "");
+
+        let response = send_json_rpc(&ctx, &ctx.ws_sess_id, json!([])).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["error"]["code"], -32600);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_send_session_expired() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let expired = ctx.nitram.insert().await;
+        let db_session = UserSession {
+            id: expired,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now() - Duration::seconds(1),
+        };
+        ctx.nitram._auth_ws_session(expired, db_session).await;
+        let mut outbox = ctx.nitram.connect(&expired).await;
+        let req = json!({
+            "id": "1",
+            "method": "MockPrivate",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "1",
+            "method": "MockPrivate",
+            "response": "(~ session expired ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &expired).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        let server_message = outbox.recv().await.unwrap();
+        assert_eq!(server_message.topic, "nitram_session_expired");
+
+        // The session was downgraded to anonymous
+        let res = json!({
+            "id": "1",
+            "method": "MockPrivate",
+            "response": "(~ not authorized ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &expired).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_session_expiring_and_refresh() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .add_private_handler("MockCount", mock_count_handler)
+            .add_public_handler("MockRefresh", mock_refresh_handler)
+            .set_session_expiring_warning(60)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let db_session = UserSession {
+            id: ws_sess_id,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now() + Duration::seconds(30),
+        };
+        nitram._auth_ws_session(ws_sess_id, db_session).await;
+        let mut outbox = nitram.connect(&ws_sess_id).await;
+
+        // Told once that the session expires soon
+        assert!(!nitram.check_expiry(&ws_sess_id).await);
+        let server_message = outbox.recv().await.unwrap();
+        assert_eq!(server_message.topic, "nitram_session_expiring");
+        assert!(server_message.payload["expires_at"].is_string());
+        assert!(!nitram.check_expiry(&ws_sess_id).await);
+        assert!(outbox.try_recv().is_err());
+
+        let count = json!({ "id": "1", "method": "MockCount", "params": { "code": "" } });
+        nitram.send(count.to_string(), &ws_sess_id).await;
+        let register = json!({
+            "id": "2",
+            "method": "nitram_topic_register",
+            "params": { "topic": "Messages", "handler_params": {} },
+        });
+        nitram.send(register.to_string(), &ws_sess_id).await;
+
+        // Only the user of the session can refresh it
+        let refresh = |user_id: &str| {
+            json!({ "id": "3", "method": "MockRefresh", "params": { "code": user_id } }).to_string()
+        };
+        let response = nitram.send(refresh("other_user"), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!(false));
+        let response = nitram.send(refresh("fake_user"), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!(true));
+
+        // The store and topic registrations are kept
+        let response = nitram.send(count.to_string(), &ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!(2));
+        assert_eq!(nitram.publish("Messages", json!("hello")).await, 1);
+        assert_eq!(outbox.recv().await.unwrap().topic, "Messages");
+        assert!(!nitram.check_expiry(&ws_sess_id).await);
+        assert!(outbox.try_recv().is_err());
+
+        // Anonymous sessions can't be refreshed
+        let anonym = nitram.insert().await;
+        let response = nitram.send(refresh("fake_user"), &anonym).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed["response"], json!(false));
+        Ok(())
+    }
+
+    /// Registers topics while the session is refreshed, no registration is lost
+    async fn register_while_refreshing(backend: Arc<dyn SessionBackend>) {
+        let ws_sess_id = Uuid::new_v4();
+        let user_session = UserSession {
+            id: ws_sess_id,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now(),
+        };
+        backend.auth(ws_sess_id, user_session).await;
+
+        let expires_at = Utc::now() + Duration::hours(1);
+        let tasks = (0..1000).map(|i| {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                let topic = format!("Topic{i}");
+                let (registered, refreshed) = tokio::join!(
+                    backend.register_topic(&ws_sess_id, &topic, json!({})),
+                    backend.refresh(&ws_sess_id, expires_at),
+                );
+                assert!(registered && refreshed);
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+
+        let Some(NitramSession::Authenticated {
+            user_session,
+            topics_registered,
+            ..
+        }) = backend.get(&ws_sess_id).await
+        else {
+            panic!("expected an authenticated session");
+        };
+        assert_eq!(user_session.expires_at, expires_at);
+        assert_eq!(topics_registered.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_subscribers() {
+        let backend = MemoryBackend::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        for ws_sess_id in [alice, bob] {
+            let user_session = UserSession {
+                id: ws_sess_id,
+                user_id: ws_sess_id.to_string(),
+                expires_at: Utc::now() + Duration::hours(1),
+            };
+            backend.auth(ws_sess_id, user_session).await;
+            backend
+                .register_topic(&ws_sess_id, "Messages", json!({}))
+                .await;
+        }
+        backend.register_topic(&alice, "News", json!({})).await;
+        let mut subscribers = backend.subscribers("Messages").await;
+        subscribers.sort();
+        let mut expected = vec![alice, bob];
+        expected.sort();
+        assert_eq!(subscribers, expected);
+
+        // Replacing a session keeps the topics it is still registered to
+        let session = backend.get(&alice).await.unwrap();
+        backend.insert(alice, session).await;
+        assert_eq!(backend.subscribers("News").await, vec![alice]);
+
+        backend.deregister_topic(&bob, "Messages").await;
+        assert_eq!(backend.subscribers("Messages").await, vec![alice]);
+        backend.insert(alice, NitramSession::Anonymous).await;
+        assert!(backend.subscribers("Messages").await.is_empty());
+        assert!(backend.subscribers("News").await.is_empty());
+
+        backend.register_topic(&bob, "News", json!({})).await;
+        backend.remove(&bob).await;
+        assert!(backend.subscribers("News").await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_refresh_is_atomic() {
+        register_while_refreshing(Arc::new(MemoryBackend::new())).await;
+
+        let path = std::env::temp_dir().join(format!("nitram-{}.json", Uuid::new_v4()));
+        let backend = Arc::new(FileBackend::open(&path).unwrap());
+        register_while_refreshing(backend.clone()).await;
+        backend.flush().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_logout() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "id": "1",
+            "method": "nitram_logout",
+            "params": {},
+        });
+        let res = json!({
+            "id": "1",
+            "method": "nitram_logout",
+            "response": true,
+            "ok": true
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        let req = json!({
+            "id": "2",
+            "method": "MockPrivate",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "2",
+            "method": "MockPrivate",
+            "response": "(~ not authorized ~)",
+            "ok": false
+        });
+        let response = ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_deauth() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let mut outbox = ctx.nitram.connect(&ctx.ws_sess_id).await;
+        let req = json!({
+            "id": "1",
+            "method": "nitram_topic_register",
+            "params": {
+                "topic": "Messages",
+                "handler_params": {}
+            },
+        });
+        ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+
+        assert!(ctx.nitram.deauth(&ctx.ws_sess_id).await);
+        assert!(!ctx.nitram.deauth(&ctx.ws_sess_id).await);
+
+        // Topic registrations were dropped
+        let delivered = ctx.nitram.publish("Messages", json!("hello")).await;
+        assert_eq!(delivered, 0);
+        assert!(outbox.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_resume() -> Result<(), MethodError> {
+        let ctx = prepare().await;
+        let req = json!({
+            "id": "1",
+            "method": "nitram_topic_register",
+            "params": {
+                "topic": "Messages",
+                "handler_params": {}
+            },
+        });
+        ctx.nitram.send(req.to_string(), &ctx.ws_sess_id).await;
+        let token = ctx.nitram.resume_token(&ctx.ws_sess_id).await.unwrap();
+
+        // A session still attached to its websocket can't be taken over
+        let intruder = ctx.nitram.insert().await;
+        assert!(!ctx.nitram.resume(&token, &intruder).await);
+
+        // The websocket closes and the client reconnects
+        ctx.nitram.remove(&ctx.ws_sess_id).await;
+        let reconnected = ctx.nitram.insert().await;
+        let mut outbox = ctx.nitram.connect(&reconnected).await;
+        assert!(ctx.nitram.resume(&token, &reconnected).await);
+
+        // Auth state and topic registrations are back
+        let req = json!({
+            "id": "2",
+            "method": "MockPrivate",
+            "params": {
+                "code": "hello"
+            },
+        });
+        let res = json!({
+            "id": "2",
+            "method": "MockPrivate",
+            "response": "HELLO",
+            "ok": true
+        });
+        let response = ctx.nitram.send(req.to_string(), &reconnected).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+        assert_eq!(ctx.nitram.publish("Messages", json!("hello")).await, 1);
+        assert_eq!(outbox.recv().await.unwrap().topic, "Messages");
+
+        // A token can only be used once
+        let other = ctx.nitram.insert().await;
+        assert!(!ctx.nitram.resume(&token, &other).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_resume_disabled() -> Result<(), MethodError> {
+        let nitram = NitramBuilder::default()
+            .set_session_resume_grace_period(0)
+            .build();
+        let ws_sess_id = nitram.insert().await;
+        let token = nitram.resume_token(&ws_sess_id).await.unwrap();
+        nitram.remove(&ws_sess_id).await;
+        let reconnected = nitram.insert().await;
+        assert!(!nitram.resume(&token, &reconnected).await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_file_backend() -> Result<(), MethodError> {
+        let path = std::env::temp_dir().join(format!("nitram-{}.json", Uuid::new_v4()));
+        let build = || {
+            NitramBuilder::default()
+                .set_session_backend(FileBackend::open(&path).unwrap())
+                .add_private_handler("MockCount", mock_count_handler)
+                .build()
+        };
+        let req = json!({
+            "id": "1",
+            "method": "MockCount",
+            "params": {
+                "code": "hello"
+            },
+        });
+
+        let nitram = build();
+        let ws_sess_id = nitram.insert().await;
+        let db_session = UserSession {
+            id: ws_sess_id,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        nitram._auth_ws_session(ws_sess_id, db_session).await;
+        nitram.send(req.to_string(), &ws_sess_id).await;
+        let token = nitram.resume_token(&ws_sess_id).await.unwrap();
+        nitram.shutdown(std::time::Duration::from_secs(1)).await;
+        drop(nitram);
+
+        // The server restarts and the client reconnects
+        let nitram = build();
+        let reconnected = nitram.insert().await;
+        assert!(nitram.resume(&token, &reconnected).await);
+        let res = json!({
+            "id": "1",
+            "method": "MockCount",
+            "response": 2,
+            "ok": true
+        });
+        let response = nitram.send(req.to_string(), &reconnected).await;
+        let parsed = serde_json::from_str::<serde_json::Value>(&response).unwrap();
+        assert_eq!(parsed, res);
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_file_backend_write_delay() -> Result<(), MethodError> {
+        let path = std::env::temp_dir().join(format!("nitram-{}.json", Uuid::new_v4()));
+        let backend = FileBackend::open(&path).unwrap().set_write_delay(50);
+        for _ in 0..100 {
+            backend
+                .insert(Uuid::new_v4(), NitramSession::Anonymous)
+                .await;
+        }
+        // Nothing is written until the delay is over, then every change at once
+        assert!(!path.exists());
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let content = std::fs::read_to_string(&path).unwrap();
+        let sessions = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&content);
+        assert_eq!(sessions.unwrap().len(), 100);
+        assert!(!path.with_extension("tmp").exists());
+
+        // Pending changes are written on flush
+        backend
+            .insert(Uuid::new_v4(), NitramSession::Anonymous)
+            .await;
+        backend.flush().await;
+        assert_eq!(FileBackend::open(&path).unwrap().len().await, 101);
+
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Events(Arc<Mutex<Vec<String>>>);
+    impl FromResources for Events {}
+
+    async fn record(ctx: HookContext, event: &str) {
+        let events = ctx.resources.get::<Events>().unwrap();
+        let entry = format!("{}:{}", event, ctx.user_id.unwrap_or_default());
+        events.0.lock().await.push(entry);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_hooks() -> Result<(), MethodError> {
+        let events = Events::default();
+        let nitram = NitramBuilder::default()
+            .add_resource(events.clone())
+            .on_connect(|ctx| record(ctx, "connect"))
+            .on_auth(|ctx| record(ctx, "auth"))
+            .on_disconnect(|ctx| record(ctx, "disconnect"))
+            .build();
+
+        let ws_sess_id = nitram.insert().await;
+        let _outbox = nitram.connect(&ws_sess_id).await;
+        let db_session = UserSession {
+            id: ws_sess_id,
+            user_id: "fake_user".to_string(),
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        nitram._auth_ws_session(ws_sess_id, db_session).await;
+        nitram.remove(&ws_sess_id).await;
+
+        assert_eq!(
+            *events.0.lock().await,
+            vec!["connect:", "auth:fake_user", "disconnect:fake_user"]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,551 @@
+use std::time::Duration;
+
+use actix_web::{web, App, HttpRequest, HttpServer};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures_util::{stream, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use nitram::{
+    auth::{
+        AuthenticatedUser, Authenticator, TokenSource, WSSessionAnonymResource,
+        WSSessionAuthedResource,
+    },
+    encoding::Encoding,
+    error::MethodError,
+    stream::NitramStream,
+    ws, IntoParams, Nitram, NitramBuilder,
+};
+
+type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+#[derive(Deserialize, Serialize)]
+struct SleepParams {
+    millis: u64,
+}
+impl IntoParams for SleepParams {}
+
+async fn sleep_handler(
+    _session: WSSessionAnonymResource,
+    params: SleepParams,
+) -> Result<u64, MethodError> {
+    tokio::time::sleep(Duration::from_millis(params.millis)).await;
+    Ok(params.millis)
+}
+
+/// Streams 3 items, `millis` apart
+async fn ticks_handler(
+    _session: WSSessionAnonymResource,
+    params: SleepParams,
+) -> Result<NitramStream<u64>, MethodError> {
+    Ok(Box::pin(stream::iter(0..3).then(move |tick| async move {
+        tokio::time::sleep(Duration::from_millis(params.millis)).await;
+        Ok(tick)
+    })))
+}
+
+/// Authenticates the `good` token as alice
+struct MockAuthenticator;
+
+#[async_trait(?Send)]
+impl Authenticator for MockAuthenticator {
+    async fn authenticate(&self, req: &HttpRequest) -> Option<AuthenticatedUser> {
+        let token = TokenSource::find(&TokenSource::DEFAULTS, req)?;
+        (token == "good").then(|| AuthenticatedUser {
+            user_id: "alice".to_string(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        })
+    }
+}
+
+async fn whoami_handler(
+    session: WSSessionAuthedResource,
+    _params: SleepParams,
+) -> Result<String, MethodError> {
+    Ok(session.user_id)
+}
+
+/// Starts a server on a random port and returns its websocket url
+fn serve(nitram: &Nitram) -> String {
+    let nitram = nitram.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(nitram.clone()))
+            .route("/ws", web::get().to(ws::handler))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("ws://{}/ws", addr)
+}
+
+/// Starts a server on a random port and connects a client to it
+async fn connect(nitram: &Nitram) -> Client {
+    let (client, _) = connect_async(serve(nitram)).await.unwrap();
+    client
+}
+
+/// Next message, skipping pings
+async fn next_message(client: &mut Client) -> Message {
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for a message")
+            .unwrap()
+            .unwrap();
+        if !matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+            return msg;
+        }
+    }
+}
+
+/// Next response, skipping server messages
+async fn next_response(client: &mut Client) -> Value {
+    loop {
+        if let Message::Text(text) = next_message(client).await {
+            let value: Value = serde_json::from_str(&text).unwrap();
+            if value.get("topic").is_none() {
+                return value;
+            }
+        }
+    }
+}
+
+/// Next server message, as JSON
+async fn next_server_message(client: &mut Client) -> Value {
+    let Message::Text(text) = next_message(client).await else {
+        panic!("expected a text frame");
+    };
+    serde_json::from_str(&text).unwrap()
+}
+
+async fn send_sleeps(client: &mut Client, millis: &[u64]) {
+    for (i, millis) in millis.iter().enumerate() {
+        let req = json!({
+            "id": i.to_string(),
+            "method": "Sleep",
+            "params": { "millis": millis },
+        });
+        client.send(Message::text(req.to_string())).await.unwrap();
+    }
+}
+
+#[actix_web::test]
+async fn test_requests_are_pipelined() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[500, 0]).await;
+
+    // The fast request is not blocked by the slow one
+    assert_eq!(next_response(&mut client).await["id"], "1");
+    assert_eq!(next_response(&mut client).await["id"], "0");
+}
+
+#[actix_web::test]
+async fn test_max_concurrent_requests() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_max_concurrent_requests(1)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[200, 0]).await;
+
+    assert_eq!(next_response(&mut client).await["id"], "0");
+    assert_eq!(next_response(&mut client).await["id"], "1");
+}
+
+#[actix_web::test]
+async fn test_cancel_when_limit_is_reached() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_max_concurrent_requests(1)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[3000]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let cancel = json!({ "id": "1", "method": "nitram_cancel", "params": { "id": "0" } });
+    client
+        .send(Message::text(cancel.to_string()))
+        .await
+        .unwrap();
+
+    // The cancel doesn't wait for the request it cancels
+    let started = std::time::Instant::now();
+    let mut responses = [
+        next_response(&mut client).await,
+        next_response(&mut client).await,
+    ];
+    responses.sort_by_key(|response| response["id"].to_string());
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(responses[0]["response"], "(~ cancelled ~)");
+    assert_eq!(responses[1]["response"], true);
+}
+
+#[actix_web::test]
+async fn test_cancel_in_batch_when_limit_is_reached() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_max_concurrent_requests(1)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[3000]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let cancel = json!([{ "id": "1", "method": "nitram_cancel", "params": { "id": "0" } }]);
+    client
+        .send(Message::text(cancel.to_string()))
+        .await
+        .unwrap();
+
+    let started = std::time::Instant::now();
+    let mut responses = [
+        next_response(&mut client).await,
+        next_response(&mut client).await,
+    ];
+    responses.sort_by_key(Value::is_array);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(responses[0]["response"], "(~ cancelled ~)");
+    assert_eq!(responses[1][0]["response"], true);
+}
+
+#[actix_web::test]
+async fn test_cancel_pending_request() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_max_concurrent_requests(1)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[3000, 0]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let cancel = json!({ "id": "2", "method": "nitram_cancel", "params": { "id": "1" } });
+    client
+        .send(Message::text(cancel.to_string()))
+        .await
+        .unwrap();
+
+    // The request waiting for a permit is dropped, not handled after "0"
+    let started = std::time::Instant::now();
+    let cancelled = next_response(&mut client).await;
+    assert_eq!(
+        cancelled,
+        json!({ "id": "1", "method": "Sleep", "response": "(~ cancelled ~)", "ok": false })
+    );
+    assert_eq!(next_response(&mut client).await["response"], true);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[actix_web::test]
+async fn test_max_pending_requests() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_max_concurrent_requests(1)
+        .set_max_pending_requests(1)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    send_sleeps(&mut client, &[500, 0, 0]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let started = std::time::Instant::now();
+    client.send(Message::Ping("ping".into())).await.unwrap();
+
+    // The socket is not read while a request waits
+    loop {
+        let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for the pong")
+            .unwrap()
+            .unwrap();
+        if matches!(msg, Message::Pong(_)) {
+            break;
+        }
+    }
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[actix_web::test]
+async fn test_stream() {
+    let nitram = NitramBuilder::default()
+        .add_public_stream_handler("Ticks", ticks_handler)
+        .build();
+    let mut client = connect(&nitram).await;
+    let req = json!({ "id": "1", "method": "Ticks", "params": { "millis": 10 } });
+    client.send(Message::text(req.to_string())).await.unwrap();
+
+    for tick in 0..3 {
+        let partial = next_response(&mut client).await;
+        assert_eq!(
+            partial,
+            json!({ "id": "1", "method": "Ticks", "partial": tick })
+        );
+    }
+    let response = next_response(&mut client).await;
+    assert_eq!(
+        response,
+        json!({ "id": "1", "method": "Ticks", "response": 3, "ok": true })
+    );
+}
+
+#[actix_web::test]
+async fn test_hello() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .add_private_handler("PrivateSleep", sleep_handler)
+        .build();
+    let mut client = connect(&nitram).await;
+
+    let hello = next_server_message(&mut client).await;
+    assert_eq!(hello["topic"], "nitram_hello");
+    let payload = &hello["payload"];
+    assert!(payload["ws_session_id"].is_string());
+    assert!(payload["server_time"].is_string());
+    assert_eq!(payload["protocol_version"], "1");
+    assert_eq!(payload["encoding"], "json");
+    assert_eq!(payload["ping_interval_in_seconds"], 30);
+    assert_eq!(payload["timeout_in_seconds"], 90);
+    assert_eq!(payload["max_frame_size"], 128 * 1024);
+    assert_eq!(
+        payload["public"],
+        json!({ "methods": ["Sleep"], "topics": [] })
+    );
+    assert_eq!(
+        payload["private"],
+        json!({ "methods": ["PrivateSleep"], "topics": [] })
+    );
+}
+
+#[actix_web::test]
+async fn test_upgrade_required() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .build();
+    let url = format!("{}?nitram_protocol=3", serve(&nitram));
+    let (mut client, _) = connect_async(url).await.unwrap();
+
+    let hello = next_server_message(&mut client).await;
+    assert_eq!(
+        hello["payload"]["supported_protocol_versions"],
+        json!(["1", "2", "jsonrpc"])
+    );
+    let upgrade_required = next_server_message(&mut client).await;
+    assert_eq!(upgrade_required["topic"], "nitram_upgrade_required");
+    assert_eq!(
+        upgrade_required["payload"],
+        json!({ "requested": "3", "supported": ["1", "2", "jsonrpc"] })
+    );
+}
+
+#[actix_web::test]
+async fn test_handshake_authentication() {
+    let nitram = NitramBuilder::default()
+        .set_authenticator(MockAuthenticator)
+        .add_private_handler("Whoami", whoami_handler)
+        .build();
+    let url = serve(&nitram);
+    let req = json!({ "id": "1", "method": "Whoami", "params": { "millis": 0 } });
+
+    let requests = [
+        ("Authorization", "Bearer good"),
+        ("Cookie", "nitram_token=good"),
+        ("Sec-WebSocket-Protocol", "nitram.json, nitram.token.good"),
+    ];
+    for (header, value) in requests {
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(header, value.parse().unwrap());
+        let (mut client, _) = connect_async(request).await.unwrap();
+        let hello = next_server_message(&mut client).await;
+        assert_eq!(hello["payload"]["user_id"], "alice");
+        // No `Authenticate` needed
+        client.send(Message::text(req.to_string())).await.unwrap();
+        assert_eq!(next_response(&mut client).await["response"], "alice");
+    }
+
+    let (mut client, _) = connect_async(format!("{}?nitram_token=good", url))
+        .await
+        .unwrap();
+    client.send(Message::text(req.to_string())).await.unwrap();
+    assert_eq!(next_response(&mut client).await["response"], "alice");
+
+    // Wrong or missing tokens start anonymous
+    let (mut client, _) = connect_async(format!("{}?nitram_token=bad", url))
+        .await
+        .unwrap();
+    let hello = next_server_message(&mut client).await;
+    assert_eq!(hello["payload"]["user_id"], Value::Null);
+    client.send(Message::text(req.to_string())).await.unwrap();
+    assert_eq!(
+        next_response(&mut client).await["response"],
+        "(~ not authorized ~)"
+    );
+}
+
+/// Next binary frame, decoded
+async fn next_binary(client: &mut Client, encoding: Encoding) -> Value {
+    match next_message(client).await {
+        Message::Binary(bytes) => encoding.decode(&bytes).unwrap(),
+        msg => panic!("expected a binary frame, got {:?}", msg),
+    }
+}
+
+#[actix_web::test]
+async fn test_binary_encoding() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .build();
+    let url = serve(&nitram);
+    let req = json!({ "id": "1", "method": "Sleep", "params": { "millis": 0 } });
+
+    // Negotiated with the subprotocol
+    let mut request = url.as_str().into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "nitram.msgpack".parse().unwrap());
+    let (mut client, response) = connect_async(request).await.unwrap();
+    assert_eq!(
+        response.headers()["Sec-WebSocket-Protocol"],
+        "nitram.msgpack"
+    );
+    let encoding = Encoding::MessagePack;
+    let hello = next_binary(&mut client, encoding).await;
+    assert_eq!(hello["topic"], "nitram_hello");
+    let server_message = next_binary(&mut client, encoding).await;
+    assert_eq!(server_message["topic"], "nitram_resume_token");
+    let payload = encoding.encode(&req);
+    client.send(Message::binary(payload)).await.unwrap();
+    assert_eq!(next_binary(&mut client, encoding).await["response"], 0);
+
+    // Text frames are still JSON
+    client.send(Message::text(req.to_string())).await.unwrap();
+    assert_eq!(next_response(&mut client).await["response"], 0);
+
+    // Negotiated with the query parameter
+    let (mut client, _) = connect_async(format!("{}?nitram_encoding=cbor", url))
+        .await
+        .unwrap();
+    let encoding = Encoding::Cbor;
+    let hello = next_binary(&mut client, encoding).await;
+    assert_eq!(hello["topic"], "nitram_hello");
+    let server_message = next_binary(&mut client, encoding).await;
+    assert_eq!(server_message["topic"], "nitram_resume_token");
+    client
+        .send(Message::binary(encoding.encode(&req)))
+        .await
+        .unwrap();
+    assert_eq!(next_binary(&mut client, encoding).await["id"], "1");
+}
+
+/// Waits until every task of the closed websockets has ended
+async fn wait_for_tasks_to_end(nitram: &Nitram) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while nitram.running_tasks() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("tasks still running after disconnect");
+}
+
+#[actix_web::test]
+async fn test_tasks_end_on_close() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_server_messages_interval(10)
+        .build();
+    let mut client = connect(&nitram).await;
+    send_sleeps(&mut client, &[0]).await;
+    next_response(&mut client).await;
+    assert!(nitram.running_tasks() > 0);
+
+    // Leave a request in flight
+    send_sleeps(&mut client, &[10_000]).await;
+    client.close(None).await.unwrap();
+
+    wait_for_tasks_to_end(&nitram).await;
+}
+
+#[actix_web::test]
+async fn test_tasks_end_on_dropped_connection() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .set_server_messages_interval(10)
+        .build();
+    let mut client = connect(&nitram).await;
+    send_sleeps(&mut client, &[0]).await;
+    next_response(&mut client).await;
+    assert!(nitram.running_tasks() > 0);
+
+    drop(client);
+
+    wait_for_tasks_to_end(&nitram).await;
+}
+
+#[actix_web::test]
+async fn test_shutdown() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .build();
+    let url = serve(&nitram);
+    let (mut client, _) = connect_async(&url).await.unwrap();
+    next_message(&mut client).await; // hello
+    next_message(&mut client).await; // resume token
+
+    send_sleeps(&mut client, &[300]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let shutdown = {
+        let nitram = nitram.clone();
+        actix_web::rt::spawn(async move { nitram.shutdown(Duration::from_secs(5)).await })
+    };
+
+    // The client is told to reconnect
+    let Message::Text(text) = next_message(&mut client).await else {
+        panic!("expected the shutdown server message");
+    };
+    let server_message: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(server_message["topic"], "nitram_shutdown");
+
+    // The request in flight is answered before the websocket is closed
+    let Message::Text(text) = next_message(&mut client).await else {
+        panic!("expected the response");
+    };
+    let response: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(response["response"], 300);
+    let Message::Close(Some(frame)) = next_message(&mut client).await else {
+        panic!("expected a close frame");
+    };
+    assert_eq!(u16::from(frame.code), 1012);
+
+    assert!(shutdown.await.unwrap());
+    assert_eq!(nitram.running_tasks(), 0);
+
+    // New websockets are rejected
+    assert!(connect_async(&url).await.is_err());
+}
+
+#[actix_web::test]
+async fn test_shutdown_deadline() {
+    let nitram = NitramBuilder::default()
+        .add_public_handler("Sleep", sleep_handler)
+        .build();
+    let mut client = connect(&nitram).await;
+    next_message(&mut client).await; // hello
+    next_message(&mut client).await; // resume token
+
+    send_sleeps(&mut client, &[10_000]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(!nitram.shutdown(Duration::from_millis(100)).await);
+    assert_eq!(nitram.running_tasks(), 0);
+}